@@ -0,0 +1,149 @@
+//! throughput of one large file served as an in-memory Full<Bytes>, streamed through user space
+//! and streamed with sendfile, each over a single keep-alive http/1.1 connection.
+//!
+//! run with `cargo bench --bench sendfile`
+
+use std::net::{SocketAddr, TcpListener as StdTcpListener};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+use http_body_util::{BodyExt, Empty};
+use hyper::body::Bytes;
+use hyper::client::conn::http1;
+use hyper::header::HOST;
+use hyper::{Request, StatusCode};
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpStream;
+
+const FILE_BYTES: usize = 64 * 1024 * 1024;
+const WARMUP_REQUESTS: u32 = 2;
+const REQUESTS: u32 = 20;
+
+/// one way of serving the file
+struct Run {
+    name: &'static str,
+    in_memory: bool,
+    sendfile: bool,
+}
+
+const RUNS: [Run; 3] = [
+    Run {
+        name: "Full<Bytes> from the cache",
+        in_memory: true,
+        sendfile: false,
+    },
+    Run {
+        name: "streamed through user space",
+        in_memory: false,
+        sendfile: false,
+    },
+    Run {
+        name: "streamed with sendfile",
+        in_memory: false,
+        sendfile: true,
+    },
+];
+
+/// the server process, killed when dropped
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let root = tempfile::tempdir().unwrap();
+    std::fs::write(root.path().join("404.html"), "not found").unwrap();
+    let content: Vec<u8> = (0..FILE_BYTES).map(|index| index as u8).collect();
+    std::fs::write(root.path().join("large.bin"), content).unwrap();
+
+    println!(
+        "{} MiB file, {} requests per run",
+        FILE_BYTES / (1024 * 1024),
+        REQUESTS
+    );
+    for run in &RUNS {
+        let (_server, addr) = start_server(root.path(), run).await;
+        let throughput = measure(addr).await;
+        println!("{:<30} {:>8.0} MiB/s", run.name, throughput);
+    }
+}
+
+/// starts the server binary with a config for the run, once it accepts connections
+async fn start_server(root: &Path, run: &Run) -> (Server, SocketAddr) {
+    // the port is released again for the server to bind
+    let addr = StdTcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    // the cache and threshold are raised above the file size for the in-memory run
+    let file_limit = if run.in_memory { 2 * FILE_BYTES } else { 1024 };
+    let config = format!(
+        "[server]\nlisten = [\"{}\"]\nsendfile = {}\n\
+         [resources]\nroot = {:?}\nstream_threshold_bytes = {}\n\
+         [cache]\nmax_bytes = {}\nmax_entry_bytes = {}\n",
+        addr,
+        run.sendfile,
+        root,
+        file_limit,
+        2 * file_limit,
+        file_limit
+    );
+    let config_path = root.join("bench.toml");
+    std::fs::write(&config_path, config).unwrap();
+
+    let server = Server(
+        Command::new(env!("CARGO_BIN_EXE_web_server"))
+            .arg("--config")
+            .arg(&config_path)
+            .env("WEB_SERVER_LOG", "warn")
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap(),
+    );
+    for _ in 0..100 {
+        if TcpStream::connect(addr).await.is_ok() {
+            return (server, addr);
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("server did not start listening on {}", addr);
+}
+
+/// MiB/s downloading the file over one connection, after warming the cache
+async fn measure(addr: SocketAddr) -> f64 {
+    let io = TokioIo::new(TcpStream::connect(addr).await.unwrap());
+    let (mut sender, connection) = http1::handshake(io).await.unwrap();
+    tokio::spawn(connection);
+
+    let mut started = Instant::now();
+    for request in 0..WARMUP_REQUESTS + REQUESTS {
+        if request == WARMUP_REQUESTS {
+            started = Instant::now();
+        }
+        let req = Request::get("/large.bin")
+            .header(HOST, "localhost")
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+        sender.ready().await.unwrap();
+        let response = sender.send_request(req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let mut body = response.into_body();
+        let mut received = 0;
+        while let Some(frame) = body.frame().await {
+            if let Ok(data) = frame.unwrap().into_data() {
+                received += data.len();
+            }
+        }
+        assert_eq!(received, FILE_BYTES);
+    }
+
+    let bytes = FILE_BYTES as f64 * f64::from(REQUESTS);
+    bytes / (1024.0 * 1024.0) / started.elapsed().as_secs_f64()
+}
@@ -0,0 +1,417 @@
+use std::fmt::Write as _;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local, SecondsFormat, Utc};
+use hyper::header::{REFERER, USER_AGENT};
+use hyper::{Request, Response};
+use serde_json::json;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::task::JoinHandle;
+use tracing::{error, warn};
+
+use crate::cache::CacheStatus;
+use crate::config::{AccessLogConfig, AccessLogFormat};
+use crate::method_handlers::handler_utils::response_body::{self, ResponseBody};
+
+/// how often buffered lines are flushed and a due rotation is checked for
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// hands finished request records to the writer task without blocking the connection
+#[derive(Clone)]
+pub(crate) struct AccessLogger {
+    sender: Sender<String>,
+    format: AccessLogFormat,
+    // lines dropped because the writer fell behind, reported by the writer once it catches up
+    dropped: Arc<AtomicU64>,
+}
+
+/// details of a request kept until its response body has been sent
+pub(crate) struct AccessRecord {
+    client: SocketAddr,
+    method: String,
+    target: String,
+    version: String,
+    host: String,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    received_at: DateTime<Utc>,
+    started: Instant,
+}
+
+/// destination of the log lines
+enum LogOutput {
+    Stdout(BufWriter<tokio::io::Stdout>),
+    File(LogFile),
+}
+
+struct LogFile {
+    writer: BufWriter<File>,
+    path: PathBuf,
+    written: u64,
+    opened_at: Instant,
+    rotate_bytes: Option<u64>,
+    rotate_interval: Option<Duration>,
+}
+
+/// opens the log output and starts the writer task. The task ends, flushing what is left,
+/// once every logger clone has been dropped.
+pub(crate) async fn spawn_access_logger(
+    access_log_config: &AccessLogConfig,
+) -> io::Result<(AccessLogger, JoinHandle<()>)> {
+    let output = match &access_log_config.path {
+        Some(path) => LogOutput::File(LogFile {
+            writer: BufWriter::new(open_log_file(path).await?),
+            path: path.clone(),
+            written: fs::metadata(path).await?.len(),
+            opened_at: Instant::now(),
+            rotate_bytes: access_log_config.rotate_bytes,
+            rotate_interval: access_log_config
+                .rotate_interval_secs
+                .map(Duration::from_secs),
+        }),
+        None => LogOutput::Stdout(BufWriter::new(tokio::io::stdout())),
+    };
+
+    let (sender, receiver) = mpsc::channel(access_log_config.buffer_lines);
+    let logger = AccessLogger {
+        sender,
+        format: access_log_config.format,
+        dropped: Arc::new(AtomicU64::new(0)),
+    };
+    let writer = tokio::task::spawn(write_lines(output, receiver, Arc::clone(&logger.dropped)));
+
+    Ok((logger, writer))
+}
+
+impl AccessLogger {
+    /// wraps the response body so the request is logged once the body has been sent
+    pub(crate) fn log_response(
+        &self,
+        record: AccessRecord,
+        response: Response<ResponseBody>,
+    ) -> Response<ResponseBody> {
+        let status = response.status().as_u16();
+        let cache_status = response.extensions().get::<CacheStatus>().copied();
+        let logger = self.clone();
+
+        response.map(|body| {
+            response_body::with_completion(body, move |bytes_sent| {
+                logger.log(record.format(logger.format, status, bytes_sent, cache_status));
+            })
+        })
+    }
+
+    /// queues a line, dropping it if the writer can't keep up rather than slowing requests down
+    fn log(&self, line: String) {
+        if self.sender.try_send(line).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl AccessRecord {
+    /// records the request details needed for the log line, before the request is handed to a handler
+    pub(crate) fn from_request<B>(req: &Request<B>, client: SocketAddr) -> Self {
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .and_then(|value: &hyper::header::HeaderValue| value.to_str().ok())
+                .map(str::to_string)
+        };
+
+        Self {
+            client,
+            method: req.method().to_string(),
+            target: req
+                .uri()
+                .path_and_query()
+                .map_or_else(|| req.uri().to_string(), |target| target.to_string()),
+            version: format!("{:?}", req.version()),
+            host: crate::resource_getters::resource_key::normalise_host(req),
+            referer: header(REFERER),
+            user_agent: header(USER_AGENT),
+            received_at: Utc::now(),
+            started: Instant::now(),
+        }
+    }
+
+    /// formats the finished request as a log line without the trailing newline
+    fn format(
+        &self,
+        format: AccessLogFormat,
+        status: u16,
+        bytes_sent: u64,
+        cache_status: Option<CacheStatus>,
+    ) -> String {
+        let duration = self.started.elapsed();
+
+        if let AccessLogFormat::Json = format {
+            return json!({
+                "time": self.received_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+                "client": self.client.ip().to_string(),
+                "host": self.host,
+                "method": self.method,
+                "path": self.target,
+                "version": self.version,
+                "status": status,
+                "bytes": bytes_sent,
+                "referer": self.referer,
+                "user_agent": self.user_agent,
+                "duration_ms": (duration.as_secs_f64() * 1_000_000.0).round() / 1000.0,
+                "cache": cache_status.map(|cache_status| match cache_status {
+                    CacheStatus::Hit => "hit",
+                    CacheStatus::Miss => "miss",
+                }),
+            })
+            .to_string();
+        }
+
+        // apache common log format, a size of 0 is written as -
+        let mut line = format!(
+            "{} - - [{}] \"{} {} {}\" {} {}",
+            self.client.ip(),
+            self.received_at
+                .with_timezone(&Local)
+                .format("%d/%b/%Y:%H:%M:%S %z"),
+            self.method,
+            escape(&self.target),
+            self.version,
+            status,
+            if bytes_sent == 0 {
+                "-".to_string()
+            } else {
+                bytes_sent.to_string()
+            }
+        );
+        if let AccessLogFormat::Combined = format {
+            let _ = write!(
+                line,
+                " \"{}\" \"{}\"",
+                self.referer.as_deref().map_or("-".to_string(), escape),
+                self.user_agent.as_deref().map_or("-".to_string(), escape)
+            );
+        }
+        line
+    }
+}
+
+/// writes queued lines, flushing once a second and rotating the file when it is due.
+/// Rotation is also checked on the flush tick so an idle server still rotates on time.
+async fn write_lines(
+    mut output: LogOutput,
+    mut receiver: Receiver<String>,
+    dropped: Arc<AtomicU64>,
+) {
+    let mut flush_interval = tokio::time::interval(FLUSH_INTERVAL);
+
+    loop {
+        let line = tokio::select! {
+            line = receiver.recv() => line,
+            _ = flush_interval.tick() => {
+                if let Err(err) = output.flush().await {
+                    error!(%err, "Error writing access log");
+                }
+                if let Err(err) = output.rotate_if_due().await {
+                    error!(%err, "Error rotating access log");
+                }
+                continue;
+            }
+        };
+
+        let line = match line {
+            Some(line) => line,
+            None => break,
+        };
+
+        let dropped_lines = dropped.swap(0, Ordering::Relaxed);
+        if dropped_lines > 0 {
+            warn!(
+                dropped_lines,
+                "Access log writer fell behind, lines were dropped"
+            );
+        }
+
+        if let Err(err) = output.write_line(&line).await {
+            error!(%err, "Error writing access log");
+        }
+    }
+
+    if let Err(err) = output.flush().await {
+        error!(%err, "Error writing access log");
+    }
+}
+
+impl LogOutput {
+    async fn write_line(&mut self, line: &str) -> io::Result<()> {
+        match self {
+            LogOutput::Stdout(writer) => write_with_newline(writer, line).await,
+            LogOutput::File(log_file) => {
+                if log_file.rotation_due() {
+                    log_file.rotate().await?;
+                }
+                write_with_newline(&mut log_file.writer, line).await?;
+                log_file.written += line.len() as u64 + 1;
+                Ok(())
+            }
+        }
+    }
+
+    async fn rotate_if_due(&mut self) -> io::Result<()> {
+        match self {
+            LogOutput::File(log_file) if log_file.rotation_due() => log_file.rotate().await,
+            _ => Ok(()),
+        }
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        match self {
+            LogOutput::Stdout(writer) => writer.flush().await,
+            LogOutput::File(log_file) => log_file.writer.flush().await,
+        }
+    }
+}
+
+impl LogFile {
+    fn rotation_due(&self) -> bool {
+        let size_reached = self
+            .rotate_bytes
+            .is_some_and(|rotate_bytes| self.written >= rotate_bytes);
+        let interval_elapsed = self
+            .rotate_interval
+            .is_some_and(|rotate_interval| self.opened_at.elapsed() >= rotate_interval);
+        size_reached || interval_elapsed
+    }
+
+    /// renames the current file to path.YYYYMMDD-HHMMSS and starts a new one.
+    /// A file nothing has been written to is kept, only its interval starts again.
+    async fn rotate(&mut self) -> io::Result<()> {
+        if self.written == 0 {
+            self.opened_at = Instant::now();
+            return Ok(());
+        }
+        self.writer.flush().await?;
+
+        let mut rotated_name = self.path.clone().into_os_string();
+        rotated_name.push(Local::now().format(".%Y%m%d-%H%M%S").to_string());
+        // several rotations within a second get a counter rather than overwriting each other
+        let mut rotated_path = PathBuf::from(&rotated_name);
+        let mut counter = 1;
+        while fs::try_exists(&rotated_path).await? {
+            let mut numbered_name = rotated_name.clone();
+            numbered_name.push(format!(".{}", counter));
+            rotated_path = PathBuf::from(numbered_name);
+            counter += 1;
+        }
+        fs::rename(&self.path, &rotated_path).await?;
+
+        self.writer = BufWriter::new(open_log_file(&self.path).await?);
+        self.written = 0;
+        self.opened_at = Instant::now();
+        Ok(())
+    }
+}
+
+async fn open_log_file(path: &PathBuf) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+}
+
+async fn write_with_newline<W: AsyncWrite + Unpin>(writer: &mut W, line: &str) -> io::Result<()> {
+    writer.write_all(line.as_bytes()).await?;
+    writer.write_all(b"\n").await
+}
+
+/// escapes quotes, backslashes and control characters so a field can't break the line apart
+fn escape(field: &str) -> String {
+    let mut escaped = String::with_capacity(field.len());
+    for character in field.chars() {
+        match character {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            character if character.is_control() => {
+                let _ = write!(escaped, "\\x{:02x}", character as u32);
+            }
+            character => escaped.push(character),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    /// a log file at path already holding the given content, opened long enough ago to be due for rotation
+    async fn overdue_log_file(path: &Path, content: &str) -> LogFile {
+        std::fs::write(path, content).unwrap();
+        LogFile {
+            writer: BufWriter::new(open_log_file(&path.to_path_buf()).await.unwrap()),
+            path: path.to_path_buf(),
+            written: content.len() as u64,
+            opened_at: Instant::now() - Duration::from_secs(120),
+            rotate_bytes: None,
+            rotate_interval: Some(Duration::from_secs(60)),
+        }
+    }
+
+    /// names in the directory other than the log file itself
+    fn rotated_files(dir: &Path) -> Vec<PathBuf> {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .map(|dir_entry| dir_entry.unwrap().path())
+            .filter(|path| path.file_name().unwrap() != "access.log")
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn rotates_on_the_flush_tick_without_new_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.log");
+        let output = LogOutput::File(overdue_log_file(&path, "old line\n").await);
+        let (sender, receiver) = mpsc::channel(8);
+        let writer = tokio::spawn(write_lines(output, receiver, Arc::new(AtomicU64::new(0))));
+
+        // the first tick is immediate, so the rotation shouldn't take much longer than that
+        let deadline = Instant::now() + FLUSH_INTERVAL * 3;
+        while rotated_files(dir.path()).is_empty() && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let rotated = rotated_files(dir.path());
+        assert_eq!(rotated.len(), 1);
+        assert_eq!(std::fs::read_to_string(&rotated[0]).unwrap(), "old line\n");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+
+        // the new file isn't rotated again until its own interval has passed
+        sender.send("new line".to_string()).await.unwrap();
+        drop(sender);
+        writer.await.unwrap();
+        assert_eq!(rotated_files(dir.path()).len(), 1);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new line\n");
+    }
+
+    #[tokio::test]
+    async fn keeps_an_empty_file_when_rotation_is_due() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.log");
+        let mut output = LogOutput::File(overdue_log_file(&path, "").await);
+
+        output.rotate_if_due().await.unwrap();
+        assert!(rotated_files(dir.path()).is_empty());
+        match &output {
+            LogOutput::File(log_file) => assert!(!log_file.rotation_due()),
+            LogOutput::Stdout(_) => unreachable!(),
+        }
+    }
+}
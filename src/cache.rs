@@ -1,62 +1,221 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::SystemTime;
 
 use hyper::body::Bytes;
-use hyper::Uri;
-use tokio::sync::RwLock;
+use sysinfo::System;
+use tokio::sync::Mutex;
+use tracing::{field, instrument, Span};
+
+use crate::config::CacheConfig;
+use crate::resource_getters::resource_key::ResourceKey;
 
 /// cache for holding the resource contents, when the resource was last modified, and its etag.
+/// Entries are evicted least recently used first once the byte budget is exceeded.
 pub struct Cache {
-    content: RwLock<HashMap<Uri, (Bytes, String, SystemTime, String)>>,
+    content: Mutex<CacheContent>,
+    max_bytes: usize,
+    max_entry_bytes: usize,
+    // lookups and budget evictions since startup, reported by the metrics endpoint
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+/// snapshot of the cache's size and counters
+pub(crate) struct CacheStats {
+    pub(crate) entries: usize,
+    pub(crate) bytes: usize,
+    pub(crate) hits: u64,
+    pub(crate) misses: u64,
+    pub(crate) evictions: u64,
+}
+
+/// entries plus the bookkeeping needed for lru eviction
+struct CacheContent {
+    entries: HashMap<ResourceKey, CacheEntry>,
+    // last use tick -> key, the first entry is the least recently used
+    recency: BTreeMap<u64, ResourceKey>,
+    tick: u64,
+    used_bytes: usize,
+}
+
+/// whether a response was served from the cache, recorded in the response extensions for logging
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CacheStatus {
+    Hit,
+    Miss,
+}
+
+struct CacheEntry {
+    // file the content was read from, used to invalidate it when the file changes
+    path: PathBuf,
+    resource_content: Bytes,
+    content_type: String,
+    last_modified: SystemTime,
+    etag: String,
+    last_used: u64,
+    size: usize,
 }
 
 impl Cache {
-    pub(crate) fn new() -> Arc<Self> {
+    pub(crate) fn new(cache_config: &CacheConfig) -> Arc<Self> {
+        let max_bytes = match cache_config.memory_fraction {
+            Some(fraction) => {
+                let mut system = System::new();
+                system.refresh_memory();
+                (system.available_memory() as f64 * fraction) as usize
+            }
+            None => cache_config.max_bytes,
+        };
+
         Arc::new(Self {
-            content: RwLock::new(HashMap::new()),
+            content: Mutex::new(CacheContent {
+                entries: HashMap::new(),
+                recency: BTreeMap::new(),
+                tick: 0,
+                used_bytes: 0,
+            }),
+            max_bytes,
+            max_entry_bytes: cache_config.max_entry_bytes.min(max_bytes),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
         })
     }
 
-    /// reads cache using the uri, either returning its contents and metadata or None if it's not in the cache
+    /// reads cache using the resource key, either returning its contents and metadata or None if it's not in the cache
+    #[instrument(
+        name = "cache_lookup",
+        level = "debug",
+        skip_all,
+        fields(path = key.get_path(), variant = key.get_variant(), hit = field::Empty)
+    )]
     pub(crate) async fn read_cache(
         cache: Arc<Self>,
-        uri: &Uri,
-    ) -> Option<(Bytes, String, SystemTime, String)> {
-        let content_guard = cache.content.read().await;
-        content_guard
-            .get(uri)
-            .map(|(resource_content, content_type, last_modified, etag)| {
-                (
-                    resource_content.clone(),
-                    content_type.clone(),
-                    *last_modified,
-                    etag.clone(),
-                )
-            })
+        key: &ResourceKey,
+    ) -> Option<(Bytes, String, SystemTime, String, PathBuf)> {
+        let mut content_guard = cache.content.lock().await;
+        let content = &mut *content_guard;
+
+        let entry = match content.entries.get_mut(key) {
+            Some(entry) => entry,
+            None => {
+                cache.misses.fetch_add(1, Ordering::Relaxed);
+                Span::current().record("hit", false);
+                return None;
+            }
+        };
+        cache.hits.fetch_add(1, Ordering::Relaxed);
+        Span::current().record("hit", true);
+
+        // mark as most recently used
+        content.tick += 1;
+        content.recency.remove(&entry.last_used);
+        content.recency.insert(content.tick, key.clone());
+        entry.last_used = content.tick;
+
+        Some((
+            entry.resource_content.clone(),
+            entry.content_type.clone(),
+            entry.last_modified,
+            entry.etag.clone(),
+            entry.path.clone(),
+        ))
     }
 
-    /// writes to cache
+    /// writes to cache, skipping resources larger than the per-entry limit
     pub(crate) async fn write_cache(
         cache: Arc<Self>,
-        uri: &Uri,
+        key: &ResourceKey,
+        path: &Path,
         resource_content: &Bytes,
         content_type: &str,
         last_modified: &SystemTime,
         etag: &str,
     ) {
-        // Insert into content
-        let mut content_guard = cache.content.write().await;
-        content_guard.insert(
-            uri.clone(),
-            (
-                resource_content.clone(),
-                content_type.to_string(),
-                *last_modified,
-                etag.to_owned(),
-            ),
+        let size = resource_content.len() + content_type.len() + etag.len();
+
+        let mut content_guard = cache.content.lock().await;
+        let content = &mut *content_guard;
+
+        // drop any previous version so a stale entry can't outlive a skipped write
+        content.remove(key);
+
+        if size > cache.max_entry_bytes {
+            return;
+        }
+
+        content.tick += 1;
+        content.recency.insert(content.tick, key.clone());
+        content.entries.insert(
+            key.clone(),
+            CacheEntry {
+                path: path.to_path_buf(),
+                resource_content: resource_content.clone(),
+                content_type: content_type.to_string(),
+                last_modified: *last_modified,
+                etag: etag.to_owned(),
+                last_used: content.tick,
+                size,
+            },
         );
+        content.used_bytes += size;
+
+        // evict least recently used entries until back under budget
+        while content.used_bytes > cache.max_bytes {
+            let oldest_key = match content.recency.first_key_value() {
+                Some((_, oldest_key)) => oldest_key.clone(),
+                None => break,
+            };
+            content.remove(&oldest_key);
+            cache.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// removes every entry read from one of the paths, from anywhere beneath them,
+    /// or listing the directory that directly contains them
+    pub(crate) async fn invalidate_paths(cache: Arc<Self>, paths: &[PathBuf]) {
+        let mut content_guard = cache.content.lock().await;
+        let content = &mut *content_guard;
+
+        let stale_keys: Vec<ResourceKey> = content
+            .entries
+            .iter()
+            .filter(|(_, entry)| {
+                paths.iter().any(|path| {
+                    entry.path.starts_with(path) || path.parent() == Some(entry.path.as_path())
+                })
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in stale_keys {
+            content.remove(&key);
+        }
+    }
+
+    /// removes every entry
+    pub(crate) async fn clear(cache: Arc<Self>) {
+        let mut content_guard = cache.content.lock().await;
+        content_guard.entries.clear();
+        content_guard.recency.clear();
+        content_guard.used_bytes = 0;
+    }
+
+    /// current number of entries and bytes used, along with the lookup and eviction counters
+    pub(crate) async fn stats(cache: Arc<Self>) -> CacheStats {
+        let content_guard = cache.content.lock().await;
+        CacheStats {
+            entries: content_guard.entries.len(),
+            bytes: content_guard.used_bytes,
+            hits: cache.hits.load(Ordering::Relaxed),
+            misses: cache.misses.load(Ordering::Relaxed),
+            evictions: cache.evictions.load(Ordering::Relaxed),
+        }
     }
 
     /// generates etag for content
@@ -65,4 +224,202 @@ impl Cache {
         (*resource_content).hash(&mut hasher);
         format!("{:x}", hasher.finish())
     }
+
+    /// generates etag for a streamed file from its length and modification time, as its content is never held whole
+    pub(crate) fn generate_file_etag(length: u64, last_modified: &SystemTime) -> String {
+        let modified_secs = last_modified
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_secs());
+        format!("{:x}-{:x}", length, modified_secs)
+    }
+}
+
+impl CacheContent {
+    /// removes an entry and its recency record
+    fn remove(&mut self, key: &ResourceKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.last_used);
+            self.used_bytes -= entry.size;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::Request;
+
+    use super::*;
+
+    fn cache(max_bytes: usize, max_entry_bytes: usize) -> Arc<Cache> {
+        Cache::new(&CacheConfig {
+            max_bytes,
+            max_entry_bytes,
+            memory_fraction: None,
+        })
+    }
+
+    fn key(path: &str) -> ResourceKey {
+        let req = Request::builder().uri(path).body(()).unwrap();
+        ResourceKey::from_request(&req, "site").unwrap()
+    }
+
+    /// caches content for a request path read from the same path under /srv, its size being its length
+    async fn write(cache: &Arc<Cache>, key: &ResourceKey, content: &str) {
+        let path = Path::new("/srv").join(key.get_path().trim_start_matches('/'));
+        Cache::write_cache(
+            Arc::clone(cache),
+            key,
+            &path,
+            &Bytes::copy_from_slice(content.as_bytes()),
+            "",
+            &SystemTime::UNIX_EPOCH,
+            "",
+        )
+        .await;
+    }
+
+    async fn is_cached(cache: &Arc<Cache>, path: &str) -> bool {
+        Cache::read_cache(Arc::clone(cache), &key(path))
+            .await
+            .is_some()
+    }
+
+    #[tokio::test]
+    async fn evicts_the_least_recently_used_on_overflow() {
+        let cache = cache(30, 30);
+        for path in ["/a", "/b", "/c", "/d"] {
+            write(&cache, &key(path), "0123456789").await;
+        }
+
+        let stats = Cache::stats(Arc::clone(&cache)).await;
+        assert_eq!((stats.entries, stats.bytes, stats.evictions), (3, 30, 1));
+        assert!(!is_cached(&cache, "/a").await);
+        for path in ["/b", "/c", "/d"] {
+            assert!(is_cached(&cache, path).await, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn hits_make_entries_recently_used() {
+        let cache = cache(30, 30);
+        for path in ["/a", "/b", "/c"] {
+            write(&cache, &key(path), "0123456789").await;
+        }
+        assert!(is_cached(&cache, "/a").await);
+
+        write(&cache, &key("/d"), "0123456789").await;
+        assert!(is_cached(&cache, "/a").await);
+        assert!(!is_cached(&cache, "/b").await);
+
+        // a write replacing an entry makes it recently used too
+        write(&cache, &key("/c"), "9876543210").await;
+        write(&cache, &key("/e"), "0123456789").await;
+        assert!(!is_cached(&cache, "/d").await);
+        assert!(is_cached(&cache, "/c").await);
+    }
+
+    #[tokio::test]
+    async fn rejects_entries_over_the_entry_limit() {
+        let cache = cache(100, 10);
+        write(&cache, &key("/large"), "01234567890").await;
+        assert!(!is_cached(&cache, "/large").await);
+
+        // a new version too large to cache drops the old one rather than leaving it stale
+        write(&cache, &key("/file"), "small").await;
+        write(&cache, &key("/file"), "much too large").await;
+        assert!(!is_cached(&cache, "/file").await);
+        let stats = Cache::stats(Arc::clone(&cache)).await;
+        assert_eq!((stats.entries, stats.bytes, stats.evictions), (0, 0, 0));
+
+        // the entry limit never exceeds the whole budget
+        let cache = self::cache(10, 100);
+        write(&cache, &key("/large"), "01234567890").await;
+        assert!(!is_cached(&cache, "/large").await);
+    }
+
+    #[tokio::test]
+    async fn invalidates_paths_beneath_and_listings_above() {
+        let cache = cache(1000, 1000);
+        for path in [
+            "/dir/a.txt",
+            "/dir/sub/b.txt",
+            "/dir/subway.txt",
+            "/other.txt",
+        ] {
+            write(&cache, &key(path), "content").await;
+        }
+        // listings of /dir and of the root, as html and json
+        write(
+            &cache,
+            &key("/dir").with_variant("html".to_string()),
+            "listing",
+        )
+        .await;
+        write(
+            &cache,
+            &key("/dir").with_variant("json".to_string()),
+            "listing",
+        )
+        .await;
+        write(
+            &cache,
+            &key("/").with_variant("html".to_string()),
+            "listing",
+        )
+        .await;
+
+        Cache::invalidate_paths(Arc::clone(&cache), &[PathBuf::from("/srv/dir/sub")]).await;
+        for (path, cached) in [
+            ("/dir/a.txt", true),
+            ("/dir/sub/b.txt", false),
+            ("/dir/subway.txt", true),
+            ("/other.txt", true),
+        ] {
+            assert_eq!(is_cached(&cache, path).await, cached, "{}", path);
+        }
+        for (listing, cached) in [("/dir", false), ("/", true)] {
+            for variant in ["html", "json"] {
+                let key = key(listing).with_variant(variant.to_string());
+                let is_cached = Cache::read_cache(Arc::clone(&cache), &key).await.is_some();
+                assert_eq!(
+                    is_cached,
+                    cached && variant == "html",
+                    "{} {}",
+                    listing,
+                    variant
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_usage_and_lookups() {
+        let cache = cache(1000, 1000);
+        Cache::write_cache(
+            Arc::clone(&cache),
+            &key("/a"),
+            Path::new("/srv/a"),
+            &Bytes::from_static(b"content"),
+            "text/plain",
+            &SystemTime::UNIX_EPOCH,
+            "etag",
+        )
+        .await;
+        write(&cache, &key("/b"), "more content").await;
+        assert!(is_cached(&cache, "/a").await);
+        assert!(is_cached(&cache, "/a").await);
+        assert!(!is_cached(&cache, "/missing").await);
+
+        let stats = Cache::stats(Arc::clone(&cache)).await;
+        assert_eq!(stats.entries, 2);
+        assert_eq!(
+            stats.bytes,
+            "content".len() + "text/plain".len() + "etag".len() + 12
+        );
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (2, 1, 0));
+
+        Cache::clear(Arc::clone(&cache)).await;
+        let stats = Cache::stats(Arc::clone(&cache)).await;
+        assert_eq!((stats.entries, stats.bytes, stats.hits), (0, 0, 2));
+    }
 }
@@ -0,0 +1,1086 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::Deserialize;
+
+/// config file read from the working directory when no other path is given
+const DEFAULT_CONFIG_FILE: &str = "web_server.toml";
+
+/// environment variable naming the config file
+const CONFIG_PATH_ENV: &str = "WEB_SERVER_CONFIG";
+
+/// content codings responses can be compressed with
+const SUPPORTED_ENCODINGS: &[&str] = &["br", "zstd", "gzip"];
+
+/// flags accepted on the command line, all of which take a value
+const FLAGS: &[&str] = &[
+    "-c",
+    "--config",
+    "-l",
+    "--listen",
+    "-r",
+    "--root",
+    "--index",
+    "--not-found",
+    "--cache-max-age",
+];
+
+const USAGE: &str = "Usage: web_server [OPTIONS]
+
+Options:
+  -c, --config <PATH>        TOML config file (default: ./web_server.toml if present)
+  -l, --listen <ADDR>        address to listen on, may be repeated (default: 127.0.0.1:8080)
+  -r, --root <DIR>           document root (default: ./resources)
+      --index <FILE>         index file served for directories, may be repeated to try
+                             several in order (default: index.html)
+      --not-found <PATH>     404 page, relative paths are resolved against the root
+      --cache-max-age <SECS> max-age sent in Cache-Control headers
+  -h, --help                 print this message
+
+Every option can also be set with an environment variable: WEB_SERVER_CONFIG,
+WEB_SERVER_LISTEN (comma separated), WEB_SERVER_ROOT, WEB_SERVER_INDEX (comma separated),
+WEB_SERVER_NOT_FOUND and WEB_SERVER_CACHE_MAX_AGE. Flags take precedence over
+environment variables, which take precedence over the config file.
+
+Log output is filtered with WEB_SERVER_LOG, e.g. WEB_SERVER_LOG=debug (default: info).";
+
+/// server configuration, built from defaults, the config file, env vars and cli flags (in that order)
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub(crate) server: ServerConfig,
+    pub(crate) resources: ResourceConfig,
+    pub(crate) handlers: HandlerConfig,
+    pub(crate) cache: CacheConfig,
+    pub(crate) compression: CompressionConfig,
+    pub(crate) watch: WatchConfig,
+    pub(crate) http2: Http2Config,
+    pub(crate) tls: TlsConfig,
+    pub(crate) redirect: RedirectConfig,
+    pub(crate) vhosts: Vec<VirtualHostConfig>,
+    pub(crate) access_log: AccessLogConfig,
+    pub(crate) admin: AdminConfig,
+    pub(crate) write: WriteConfig,
+    pub(crate) trace: TraceConfig,
+    pub(crate) proxy: ProxyConfig,
+}
+
+/// settings for the listening sockets
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub(crate) listen: Vec<SocketAddr>,
+    pub(crate) drain_timeout_secs: u64,
+    // streamed files are sent with sendfile on plaintext http/1.1 connections (linux only)
+    pub(crate) sendfile: bool,
+}
+
+/// settings for where resources are read from
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResourceConfig {
+    pub(crate) root: PathBuf,
+    // tried in order for every directory request
+    pub(crate) index_files: Vec<String>,
+    pub(crate) not_found_page: PathBuf,
+    pub(crate) mime_types: HashMap<String, String>,
+    pub(crate) sniff_extensionless: bool,
+    pub(crate) headers: HashMap<String, String>,
+    pub(crate) autoindex: AutoindexConfig,
+    // files larger than this are streamed from disk instead of read into memory and cached
+    pub(crate) stream_threshold_bytes: u64,
+    // serve file.br, file.gz or file.zst in place of file when the client accepts that encoding
+    pub(crate) precompressed: bool,
+}
+
+/// settings for generated listings of directories that have no index file
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AutoindexConfig {
+    pub(crate) enabled: bool,
+    pub(crate) show_hidden: bool,
+}
+
+/// a site served for a set of hostnames, "*.example.com" matches any subdomain of example.com.
+/// Requests matching no vhost go to the default vhost if there is one, otherwise to [resources].
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VirtualHostConfig {
+    pub(crate) names: Vec<String>,
+    #[serde(default)]
+    pub(crate) default: bool,
+    #[serde(default)]
+    pub(crate) resources: ResourceConfig,
+}
+
+/// the site chosen for a request, with the namespace its cache entries are stored under
+pub(crate) struct Site<'a> {
+    pub(crate) namespace: &'a str,
+    pub(crate) resources: &'a ResourceConfig,
+}
+
+/// settings for the headers sent by the method handlers
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HandlerConfig {
+    pub(crate) server_name: String,
+    pub(crate) cache_max_age: u64,
+    pub(crate) expiry_days: u64,
+}
+
+/// settings for the in-memory resource cache
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    pub(crate) max_bytes: usize,
+    pub(crate) max_entry_bytes: usize,
+    pub(crate) memory_fraction: Option<f64>,
+}
+
+/// settings for compressing responses on the fly, each compressed variant is cached
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressionConfig {
+    pub(crate) enabled: bool,
+    pub(crate) min_bytes: usize,
+    // content codings offered, in the order preferred when the client weighs them equally,
+    // also used to pick precompressed files
+    pub(crate) encodings: Vec<String>,
+}
+
+/// settings for invalidating cached resources when files change on disk
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WatchConfig {
+    pub(crate) enabled: bool,
+    pub(crate) debounce_ms: u64,
+    pub(crate) poll_interval_ms: u64,
+}
+
+/// settings for http/2 connections, negotiated alongside http/1.1 on every listener
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Http2Config {
+    pub(crate) enabled: bool,
+    pub(crate) max_concurrent_streams: u32,
+    pub(crate) initial_stream_window_size: Option<u32>,
+    pub(crate) initial_connection_window_size: Option<u32>,
+    pub(crate) adaptive_window: bool,
+    pub(crate) keep_alive_interval_secs: Option<u64>,
+    pub(crate) keep_alive_timeout_secs: u64,
+}
+
+/// settings for the https listeners
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub(crate) listen: Vec<SocketAddr>,
+    pub(crate) certificates: Vec<CertificateConfig>,
+    pub(crate) reload: bool,
+}
+
+/// a PEM certificate chain and key, and the SNI hostnames (wildcards allowed) it is served for
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CertificateConfig {
+    #[serde(default)]
+    pub(crate) hostnames: Vec<String>,
+    pub(crate) cert: PathBuf,
+    pub(crate) key: PathBuf,
+}
+
+/// settings for plaintext listeners that redirect everything except the passthrough paths to https
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedirectConfig {
+    pub(crate) listen: Vec<SocketAddr>,
+    pub(crate) https_port: u16,
+    pub(crate) status: u16,
+    pub(crate) host: Option<String>,
+    pub(crate) passthrough: Vec<String>,
+}
+
+/// settings for the admin listeners, which serve metrics apart from the sites. Metrics are
+/// only collected when at least one admin address is configured
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    pub(crate) listen: Vec<SocketAddr>,
+    pub(crate) metrics_path: String,
+}
+
+/// settings for requests that change the document root, which are refused unless enabled
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WriteConfig {
+    pub(crate) enabled: bool,
+    // accepted as Authorization: Bearer <token>
+    pub(crate) tokens: Vec<String>,
+    pub(crate) max_bytes: u64,
+    // DELETE may remove empty directories as well as files
+    pub(crate) delete_directories: bool,
+    pub(crate) upload: UploadConfig,
+}
+
+/// settings for POST uploads, which are authorized like the other writes. Each file is limited
+/// to write.max_bytes
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UploadConfig {
+    // request path accepting uploads on every site, POST is not served when unset
+    pub(crate) endpoint: Option<String>,
+    pub(crate) directory: PathBuf,
+    pub(crate) max_files: usize,
+    pub(crate) max_request_bytes: u64,
+    // total size the files in the upload directory may reach
+    pub(crate) quota_bytes: Option<u64>,
+}
+
+/// settings for the TRACE method, off by default as echoing requests enables cross-site tracing
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TraceConfig {
+    pub(crate) enabled: bool,
+    // echoed as [redacted], on top of Authorization, Proxy-Authorization and Cookie
+    pub(crate) redact_headers: Vec<String>,
+    // longest echoed request, larger requests are refused
+    pub(crate) max_bytes: usize,
+}
+
+/// settings for tunnelling CONNECT requests, off by default so the server isn't an open proxy
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyConfig {
+    pub(crate) enabled: bool,
+    // user:password pairs accepted as Proxy-Authorization: Basic
+    pub(crate) credentials: Vec<String>,
+    // host:port destinations that may be tunnelled to, * as the port allows any
+    pub(crate) allow: Vec<String>,
+    pub(crate) connect_timeout_secs: u64,
+}
+
+/// settings for the access log, written to stdout unless a path is given
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessLogConfig {
+    pub(crate) enabled: bool,
+    pub(crate) path: Option<PathBuf>,
+    pub(crate) format: AccessLogFormat,
+    // the file is renamed with a timestamp suffix and reopened once either limit is reached
+    pub(crate) rotate_bytes: Option<u64>,
+    pub(crate) rotate_interval_secs: Option<u64>,
+    // lines queued for the writer, further lines are dropped while it is full
+    pub(crate) buffer_lines: usize,
+}
+
+/// layout of access log lines. Common and combined are the standard apache formats,
+/// json additionally records the duration and whether the cache was hit
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    Common,
+    Combined,
+    Json,
+}
+
+/// error produced while loading or validating the configuration
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Argument(String),
+    Invalid(String),
+}
+
+/// values passed on the command line
+#[derive(Default)]
+struct CliArgs {
+    config: Option<PathBuf>,
+    listen: Vec<SocketAddr>,
+    root: Option<PathBuf>,
+    index_files: Vec<String>,
+    not_found_page: Option<PathBuf>,
+    cache_max_age: Option<u64>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: vec![SocketAddr::from(([127, 0, 0, 1], 8080))],
+            drain_timeout_secs: 30,
+            sendfile: true,
+        }
+    }
+}
+
+impl Default for ResourceConfig {
+    fn default() -> Self {
+        Self {
+            root: PathBuf::from("resources"),
+            index_files: vec!["index.html".to_string()],
+            not_found_page: PathBuf::from("404.html"),
+            mime_types: HashMap::new(),
+            sniff_extensionless: false,
+            headers: HashMap::new(),
+            autoindex: AutoindexConfig::default(),
+            stream_threshold_bytes: 8 * 1024 * 1024,
+            precompressed: false,
+        }
+    }
+}
+
+impl Default for HandlerConfig {
+    fn default() -> Self {
+        Self {
+            server_name: "ZACHARY-RUST-SERVER".to_string(),
+            cache_max_age: 36000,
+            expiry_days: 4,
+        }
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes: 64 * 1024 * 1024,
+            max_entry_bytes: 8 * 1024 * 1024,
+            memory_fraction: None,
+        }
+    }
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            min_bytes: 1024,
+            encodings: vec!["br".to_string(), "zstd".to_string(), "gzip".to_string()],
+        }
+    }
+}
+
+impl Default for WatchConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            debounce_ms: 100,
+            poll_interval_ms: 2000,
+        }
+    }
+}
+
+impl Default for Http2Config {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_concurrent_streams: 200,
+            initial_stream_window_size: None,
+            initial_connection_window_size: None,
+            adaptive_window: false,
+            keep_alive_interval_secs: None,
+            keep_alive_timeout_secs: 20,
+        }
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            listen: Vec::new(),
+            certificates: Vec::new(),
+            reload: true,
+        }
+    }
+}
+
+impl Default for RedirectConfig {
+    fn default() -> Self {
+        Self {
+            listen: Vec::new(),
+            https_port: 443,
+            status: 308,
+            host: None,
+            passthrough: vec!["/.well-known/".to_string()],
+        }
+    }
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            listen: Vec::new(),
+            metrics_path: "/metrics".to_string(),
+        }
+    }
+}
+
+impl Default for WriteConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            tokens: Vec::new(),
+            max_bytes: 64 * 1024 * 1024,
+            delete_directories: false,
+            upload: UploadConfig::default(),
+        }
+    }
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: None,
+            directory: PathBuf::from("uploads"),
+            max_files: 32,
+            max_request_bytes: 256 * 1024 * 1024,
+            quota_bytes: None,
+        }
+    }
+}
+
+impl Default for TraceConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            redact_headers: Vec::new(),
+            max_bytes: 64 * 1024,
+        }
+    }
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            credentials: Vec::new(),
+            allow: Vec::new(),
+            connect_timeout_secs: 10,
+        }
+    }
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: None,
+            format: AccessLogFormat::Combined,
+            rotate_bytes: None,
+            rotate_interval_secs: None,
+            buffer_lines: 8192,
+        }
+    }
+}
+
+impl Config {
+    /// loads the configuration from the process arguments and environment, then validates it
+    pub(crate) fn load() -> Result<Arc<Self>, ConfigError> {
+        let args = CliArgs::parse(std::env::args().skip(1))?;
+
+        // an explicitly named config file must exist, the default one is optional
+        let config_path = args
+            .config
+            .clone()
+            .or_else(|| std::env::var_os(CONFIG_PATH_ENV).map(PathBuf::from));
+
+        let mut config = match config_path {
+            Some(path) => Self::from_file(&path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).is_file() => {
+                Self::from_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => Self::default(),
+        };
+
+        config.apply_env()?;
+        config.apply_args(args);
+        config.validate()?;
+
+        Ok(Arc::new(config))
+    }
+
+    /// picks the site for a normalised request host: exact name, then wildcard, then the default
+    pub(crate) fn select_site(&self, host: &str) -> Site<'_> {
+        let exact_match = self
+            .vhosts
+            .iter()
+            .find(|virtual_host| virtual_host.names.iter().any(|name| name == host));
+
+        // the most specific wildcard wins, the first listed on a tie
+        let wildcard_match = || {
+            self.vhosts
+                .iter()
+                .filter_map(|virtual_host| {
+                    virtual_host
+                        .names
+                        .iter()
+                        .filter_map(|name| name.strip_prefix('*'))
+                        .filter(|suffix| host.ends_with(suffix))
+                        .map(str::len)
+                        .max()
+                        .map(|suffix_len| (virtual_host, suffix_len))
+                })
+                .rev()
+                .max_by_key(|(_, suffix_len)| *suffix_len)
+                .map(|(virtual_host, _)| virtual_host)
+        };
+
+        let default_match = || self.vhosts.iter().find(|virtual_host| virtual_host.default);
+
+        match exact_match.or_else(wildcard_match).or_else(default_match) {
+            Some(virtual_host) => Site {
+                namespace: &virtual_host.names[0],
+                resources: &virtual_host.resources,
+            },
+            None => Site {
+                namespace: "",
+                resources: &self.resources,
+            },
+        }
+    }
+
+    /// every document root that can be served
+    pub(crate) fn document_roots(&self) -> Vec<&Path> {
+        let mut roots = vec![self.resources.root.as_path()];
+        for virtual_host in &self.vhosts {
+            if !roots.contains(&virtual_host.resources.root.as_path()) {
+                roots.push(&virtual_host.resources.root);
+            }
+        }
+        roots
+    }
+
+    /// parses a TOML config file
+    fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let contents =
+            fs::read_to_string(path).map_err(|err| ConfigError::Read(path.to_path_buf(), err))?;
+        toml::from_str(&contents).map_err(|err| ConfigError::Parse(path.to_path_buf(), err))
+    }
+
+    /// parses and checks a TOML config without reading the environment or the command line
+    #[cfg(test)]
+    pub(crate) fn from_toml(contents: &str) -> Result<Arc<Self>, ConfigError> {
+        let mut config: Self =
+            toml::from_str(contents).map_err(|err| ConfigError::Parse(PathBuf::new(), err))?;
+        config.validate()?;
+        Ok(Arc::new(config))
+    }
+
+    /// overrides values with any WEB_SERVER_* environment variables that are set
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+        if let Some(listen) = env_var("WEB_SERVER_LISTEN")? {
+            self.server.listen = listen
+                .split(',')
+                .map(|addr| parse_value("WEB_SERVER_LISTEN", addr.trim()))
+                .collect::<Result<_, _>>()?;
+        }
+        if let Some(root) = env_var("WEB_SERVER_ROOT")? {
+            self.resources.root = PathBuf::from(root);
+        }
+        if let Some(index_files) = env_var("WEB_SERVER_INDEX")? {
+            self.resources.index_files = index_files
+                .split(',')
+                .map(|index_file| index_file.trim().to_string())
+                .collect();
+        }
+        if let Some(not_found_page) = env_var("WEB_SERVER_NOT_FOUND")? {
+            self.resources.not_found_page = PathBuf::from(not_found_page);
+        }
+        if let Some(max_age) = env_var("WEB_SERVER_CACHE_MAX_AGE")? {
+            self.handlers.cache_max_age = parse_value("WEB_SERVER_CACHE_MAX_AGE", &max_age)?;
+        }
+        Ok(())
+    }
+
+    /// overrides values with those given on the command line
+    fn apply_args(&mut self, args: CliArgs) {
+        if !args.listen.is_empty() {
+            self.server.listen = args.listen;
+        }
+        if let Some(root) = args.root {
+            self.resources.root = root;
+        }
+        if !args.index_files.is_empty() {
+            self.resources.index_files = args.index_files;
+        }
+        if let Some(not_found_page) = args.not_found_page {
+            self.resources.not_found_page = not_found_page;
+        }
+        if let Some(max_age) = args.cache_max_age {
+            self.handlers.cache_max_age = max_age;
+        }
+    }
+
+    /// checks the final configuration, resolving the document root and 404 page to absolute paths
+    fn validate(&mut self) -> Result<(), ConfigError> {
+        if self.server.listen.is_empty()
+            && self.tls.listen.is_empty()
+            && self.redirect.listen.is_empty()
+        {
+            return Err(ConfigError::Invalid(
+                "server.listen, tls.listen or redirect.listen must contain at least one address"
+                    .to_string(),
+            ));
+        }
+
+        if !self.tls.listen.is_empty() && self.tls.certificates.is_empty() {
+            return Err(ConfigError::Invalid(
+                "tls.listen requires at least one tls.certificates entry".to_string(),
+            ));
+        }
+
+        // canonical parent directories let the certificate watcher match event paths exactly
+        for certificate in &mut self.tls.certificates {
+            certificate.cert = absolute_file_path("tls.certificates.cert", &certificate.cert)?;
+            certificate.key = absolute_file_path("tls.certificates.key", &certificate.key)?;
+        }
+
+        self.resources.validate("resources")?;
+
+        let mut default_count = 0;
+        for (index, virtual_host) in self.vhosts.iter_mut().enumerate() {
+            let section = format!("vhosts[{}]", index);
+            if virtual_host.names.is_empty() {
+                return Err(ConfigError::Invalid(format!(
+                    "{}.names must contain at least one hostname",
+                    section
+                )));
+            }
+            for name in &mut virtual_host.names {
+                *name = name.trim_end_matches('.').to_ascii_lowercase();
+                let hostname = name.strip_prefix("*.").unwrap_or(name);
+                if hostname.is_empty()
+                    || !hostname.chars().all(|character| {
+                        character.is_ascii_alphanumeric() || matches!(character, '-' | '.')
+                    })
+                {
+                    return Err(ConfigError::Invalid(format!(
+                        "{}.names entry {:?} is not a hostname or *.wildcard",
+                        section, name
+                    )));
+                }
+            }
+            if virtual_host.default {
+                default_count += 1;
+            }
+            virtual_host
+                .resources
+                .validate(&format!("{}.resources", section))?;
+        }
+        if default_count > 1 {
+            return Err(ConfigError::Invalid(
+                "only one vhosts entry can be the default".to_string(),
+            ));
+        }
+
+        if let Some(fraction) = self.cache.memory_fraction {
+            if !(fraction > 0.0 && fraction <= 1.0) {
+                return Err(ConfigError::Invalid(format!(
+                    "cache.memory_fraction {} must be greater than 0 and at most 1",
+                    fraction
+                )));
+            }
+        }
+
+        for encoding in &self.compression.encodings {
+            if !SUPPORTED_ENCODINGS.contains(&encoding.as_str()) {
+                return Err(ConfigError::Invalid(format!(
+                    "compression.encodings entry {:?} must be one of {}",
+                    encoding,
+                    SUPPORTED_ENCODINGS.join(", ")
+                )));
+            }
+        }
+
+        if let Some(path) = &self.access_log.path {
+            let invalid = |reason: &str| {
+                ConfigError::Invalid(format!("access_log.path {}: {}", path.display(), reason))
+            };
+            let file_name = path.file_name().ok_or_else(|| invalid("not a file name"))?;
+            let directory = match path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+            let directory = directory
+                .canonicalize()
+                .map_err(|err| invalid(&err.to_string()))?;
+            self.access_log.path = Some(directory.join(file_name));
+        } else if self.access_log.rotate_bytes.is_some()
+            || self.access_log.rotate_interval_secs.is_some()
+        {
+            return Err(ConfigError::Invalid(
+                "access_log rotation requires access_log.path".to_string(),
+            ));
+        }
+        if self.access_log.buffer_lines == 0 || self.access_log.rotate_bytes == Some(0) {
+            return Err(ConfigError::Invalid(
+                "access_log.buffer_lines and access_log.rotate_bytes must be greater than 0"
+                    .to_string(),
+            ));
+        }
+        if self.access_log.rotate_interval_secs == Some(0) {
+            return Err(ConfigError::Invalid(
+                "access_log.rotate_interval_secs must be greater than 0".to_string(),
+            ));
+        }
+
+        if !self.admin.metrics_path.starts_with('/') {
+            return Err(ConfigError::Invalid(format!(
+                "admin.metrics_path {:?} must start with '/'",
+                self.admin.metrics_path
+            )));
+        }
+
+        // writes are never accepted without authentication
+        if self.write.enabled && self.write.tokens.is_empty() {
+            return Err(ConfigError::Invalid(
+                "write.enabled requires at least one write.tokens entry".to_string(),
+            ));
+        }
+        if self.write.tokens.iter().any(|token| token.is_empty()) {
+            return Err(ConfigError::Invalid(
+                "write.tokens entries must not be empty".to_string(),
+            ));
+        }
+        if self.write.max_bytes == 0 {
+            return Err(ConfigError::Invalid(
+                "write.max_bytes must be greater than 0".to_string(),
+            ));
+        }
+        if let Some(endpoint) = &self.write.upload.endpoint {
+            if !endpoint.starts_with('/') {
+                return Err(ConfigError::Invalid(format!(
+                    "write.upload.endpoint {:?} must start with '/'",
+                    endpoint
+                )));
+            }
+            // canonical so stored files can be matched against cache entries
+            self.write.upload.directory =
+                self.write.upload.directory.canonicalize().map_err(|err| {
+                    ConfigError::Invalid(format!(
+                        "write.upload.directory {}: {}",
+                        self.write.upload.directory.display(),
+                        err
+                    ))
+                })?;
+            if !self.write.upload.directory.is_dir() {
+                return Err(ConfigError::Invalid(format!(
+                    "write.upload.directory {} is not a directory",
+                    self.write.upload.directory.display()
+                )));
+            }
+        }
+        if self.write.upload.max_files == 0
+            || self.write.upload.max_request_bytes == 0
+            || self.write.upload.quota_bytes == Some(0)
+        {
+            return Err(ConfigError::Invalid(
+                "write.upload.max_files, write.upload.max_request_bytes and \
+                 write.upload.quota_bytes must be greater than 0"
+                    .to_string(),
+            ));
+        }
+
+        // lowercase to match the names hyper gives received headers
+        for name in &mut self.trace.redact_headers {
+            if hyper::header::HeaderName::from_bytes(name.as_bytes()).is_err() {
+                return Err(ConfigError::Invalid(format!(
+                    "trace.redact_headers entry {:?} is not a valid header name",
+                    name
+                )));
+            }
+            *name = name.to_ascii_lowercase();
+        }
+        if self.trace.max_bytes == 0 {
+            return Err(ConfigError::Invalid(
+                "trace.max_bytes must be greater than 0".to_string(),
+            ));
+        }
+
+        if self.proxy.enabled && self.proxy.credentials.is_empty() {
+            return Err(ConfigError::Invalid(
+                "proxy.enabled requires at least one proxy.credentials entry".to_string(),
+            ));
+        }
+        if let Some(credential) = self
+            .proxy
+            .credentials
+            .iter()
+            .find(|credential| !credential.contains(':'))
+        {
+            return Err(ConfigError::Invalid(format!(
+                "proxy.credentials entry {:?} must be user:password",
+                credential
+            )));
+        }
+        // lowercase to match hosts case-insensitively
+        for destination in &mut self.proxy.allow {
+            let valid = match destination.rsplit_once(':') {
+                Some((host, port)) => {
+                    !host.is_empty() && (port == "*" || port.parse::<u16>().is_ok())
+                }
+                None => false,
+            };
+            if !valid {
+                return Err(ConfigError::Invalid(format!(
+                    "proxy.allow entry {:?} must be host:port",
+                    destination
+                )));
+            }
+            *destination = destination.to_ascii_lowercase();
+        }
+        if self.proxy.connect_timeout_secs == 0 {
+            return Err(ConfigError::Invalid(
+                "proxy.connect_timeout_secs must be greater than 0".to_string(),
+            ));
+        }
+
+        if self.watch.poll_interval_ms == 0 {
+            return Err(ConfigError::Invalid(
+                "watch.poll_interval_ms must be greater than 0".to_string(),
+            ));
+        }
+
+        // h2 requires windows of at least the initial 65535 bytes and below 2^31
+        for (name, window_size) in [
+            (
+                "http2.initial_stream_window_size",
+                self.http2.initial_stream_window_size,
+            ),
+            (
+                "http2.initial_connection_window_size",
+                self.http2.initial_connection_window_size,
+            ),
+        ] {
+            if let Some(window_size) = window_size {
+                if !(65535..=0x7fff_ffff).contains(&window_size) {
+                    return Err(ConfigError::Invalid(format!(
+                        "{} {} must be between 65535 and 2147483647",
+                        name, window_size
+                    )));
+                }
+            }
+        }
+
+        if self.redirect.status != 301 && self.redirect.status != 308 {
+            return Err(ConfigError::Invalid(format!(
+                "redirect.status {} must be 301 or 308",
+                self.redirect.status
+            )));
+        }
+        if self.redirect.https_port == 0 {
+            return Err(ConfigError::Invalid(
+                "redirect.https_port must be greater than 0".to_string(),
+            ));
+        }
+        if let Some(prefix) = self
+            .redirect
+            .passthrough
+            .iter()
+            .find(|prefix| !prefix.starts_with('/'))
+        {
+            return Err(ConfigError::Invalid(format!(
+                "redirect.passthrough entry {:?} must start with '/'",
+                prefix
+            )));
+        }
+        if let Some(host) = &self.redirect.host {
+            if host.is_empty() || hyper::Uri::try_from(format!("https://{}/", host)).is_err() {
+                return Err(ConfigError::Invalid(format!(
+                    "redirect.host {:?} is not a valid host",
+                    host
+                )));
+            }
+        }
+
+        if self.handlers.server_name.is_empty()
+            || hyper::header::HeaderValue::from_str(&self.handlers.server_name).is_err()
+        {
+            return Err(ConfigError::Invalid(format!(
+                "handlers.server_name {:?} is not a valid header value",
+                self.handlers.server_name
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+impl ResourceConfig {
+    /// checks a site's settings, resolving its root and 404 page to absolute paths
+    fn validate(&mut self, section: &str) -> Result<(), ConfigError> {
+        self.root = match self.root.canonicalize() {
+            Ok(root) if root.is_dir() => root,
+            Ok(root) => {
+                return Err(ConfigError::Invalid(format!(
+                    "{}.root {} is not a directory",
+                    section,
+                    root.display()
+                )))
+            }
+            Err(err) => {
+                return Err(ConfigError::Invalid(format!(
+                    "{}.root {}: {}",
+                    section,
+                    self.root.display(),
+                    err
+                )))
+            }
+        };
+
+        for index_file in &self.index_files {
+            if Path::new(index_file).file_name() != Some(index_file.as_ref()) {
+                return Err(ConfigError::Invalid(format!(
+                    "{}.index_files entry {:?} must be a plain file name",
+                    section, index_file
+                )));
+            }
+        }
+
+        let not_found_page = self.root.join(&self.not_found_page);
+        if !not_found_page.is_file() {
+            return Err(ConfigError::Invalid(format!(
+                "{}.not_found_page {} is not a file",
+                section,
+                not_found_page.display()
+            )));
+        }
+        self.not_found_page = not_found_page;
+
+        // extensions are matched lowercase and without the leading dot
+        let mut mime_types = HashMap::new();
+        for (extension, mime_type) in self.mime_types.drain() {
+            let extension = extension.trim_start_matches('.').to_ascii_lowercase();
+            if extension.is_empty()
+                || !mime_type.contains('/')
+                || hyper::header::HeaderValue::from_str(&mime_type).is_err()
+            {
+                return Err(ConfigError::Invalid(format!(
+                    "{}.mime_types entry {:?} = {:?} is not a valid extension and mime type",
+                    section, extension, mime_type
+                )));
+            }
+            mime_types.insert(extension, mime_type);
+        }
+        self.mime_types = mime_types;
+
+        for (name, value) in &self.headers {
+            if hyper::header::HeaderName::from_bytes(name.as_bytes()).is_err()
+                || hyper::header::HeaderValue::from_str(value).is_err()
+            {
+                return Err(ConfigError::Invalid(format!(
+                    "{}.headers entry {:?} = {:?} is not a valid header",
+                    section, name, value
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl CliArgs {
+    /// parses the command line, accepting both `--flag value` and `--flag=value`
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, ConfigError> {
+        let mut parsed = Self::default();
+
+        while let Some(arg) = args.next() {
+            let (flag, inline_value) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => (flag.to_string(), Some(value)),
+                _ => (arg.clone(), None),
+            };
+
+            if flag == "-h" || flag == "--help" {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+
+            if !FLAGS.contains(&flag.as_str()) {
+                return Err(ConfigError::Argument(format!(
+                    "unknown option {}\n\n{}",
+                    flag, USAGE
+                )));
+            }
+
+            let value = match inline_value {
+                Some(value) => value.to_string(),
+                None => args
+                    .next()
+                    .ok_or_else(|| ConfigError::Argument(format!("{} requires a value", flag)))?,
+            };
+
+            match flag.as_str() {
+                "-c" | "--config" => parsed.config = Some(PathBuf::from(value)),
+                "-l" | "--listen" => parsed.listen.push(parse_value(&flag, &value)?),
+                "-r" | "--root" => parsed.root = Some(PathBuf::from(value)),
+                "--index" => parsed.index_files.push(value),
+                "--not-found" => parsed.not_found_page = Some(PathBuf::from(value)),
+                "--cache-max-age" => parsed.cache_max_age = Some(parse_value(&flag, &value)?),
+                _ => unreachable!("flag list and match arms out of sync"),
+            }
+        }
+
+        Ok(parsed)
+    }
+}
+
+/// makes a file path absolute by canonicalising its directory, keeping the file name as given
+fn absolute_file_path(name: &str, path: &Path) -> Result<PathBuf, ConfigError> {
+    let invalid =
+        |reason: String| ConfigError::Invalid(format!("{} {}: {}", name, path.display(), reason));
+
+    if !path.is_file() {
+        return Err(invalid("not a file".to_string()));
+    }
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| invalid("not a file".to_string()))?;
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    directory
+        .canonicalize()
+        .map(|directory| directory.join(file_name))
+        .map_err(|err| invalid(err.to_string()))
+}
+
+/// reads an environment variable, treating unset as None and non-unicode as an error
+fn env_var(name: &str) -> Result<Option<String>, ConfigError> {
+    match std::env::var(name) {
+        Ok(value) => Ok(Some(value)),
+        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(std::env::VarError::NotUnicode(_)) => Err(ConfigError::Argument(format!(
+            "{} is not valid unicode",
+            name
+        ))),
+    }
+}
+
+/// parses a flag or environment value, naming the source in the error
+fn parse_value<T: std::str::FromStr>(source: &str, value: &str) -> Result<T, ConfigError>
+where
+    T::Err: fmt::Display,
+{
+    value.parse().map_err(|err| {
+        ConfigError::Argument(format!("invalid value {:?} for {}: {}", value, source, err))
+    })
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, err) => write!(f, "could not read {}: {}", path.display(), err),
+            ConfigError::Parse(path, err) => {
+                write!(f, "could not parse {}: {}", path.display(), err)
+            }
+            ConfigError::Argument(message) | ConfigError::Invalid(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
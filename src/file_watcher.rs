@@ -0,0 +1,150 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use notify::{
+    Config as NotifyConfig, ErrorKind, Event, EventKind, PollWatcher, RecommendedWatcher,
+    RecursiveMode, Watcher,
+};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::time::{timeout_at, Instant};
+use tracing::{error, warn};
+
+use crate::cache::Cache;
+use crate::config::WatchConfig;
+
+/// events are flushed at the latest after this many debounce periods, even if they keep coming
+const MAX_DEBOUNCE_PERIODS: u32 = 10;
+
+type WatchEvent = notify::Result<Event>;
+
+/// watches the document root and evicts cache entries whose files change on disk
+struct FileWatcher {
+    cache: Arc<Cache>,
+    root: PathBuf,
+    // kept alive for as long as events are wanted, dropping it stops the watch
+    watcher: Box<dyn Watcher + Send>,
+    event_sender: UnboundedSender<WatchEvent>,
+    debounce: Duration,
+    poll_interval: Duration,
+}
+
+/// starts watching the root, using the native backend if possible and polling mtimes otherwise
+pub(crate) fn spawn_file_watcher(
+    cache: Arc<Cache>,
+    root: PathBuf,
+    watch_config: &WatchConfig,
+) -> notify::Result<()> {
+    let (event_sender, event_receiver) = mpsc::unbounded_channel();
+    let poll_interval = Duration::from_millis(watch_config.poll_interval_ms);
+
+    let watcher = match start_native_watcher(&root, event_sender.clone()) {
+        Ok(watcher) => watcher,
+        Err(err) => {
+            warn!(%err, "Native file watching unavailable, polling for changes instead");
+            start_poll_watcher(&root, event_sender.clone(), poll_interval)?
+        }
+    };
+
+    let file_watcher = FileWatcher {
+        cache,
+        root,
+        watcher,
+        event_sender,
+        debounce: Duration::from_millis(watch_config.debounce_ms),
+        poll_interval,
+    };
+    tokio::task::spawn(file_watcher.run(event_receiver));
+
+    Ok(())
+}
+
+impl FileWatcher {
+    /// collects bursts of events and invalidates the affected cache entries once they settle
+    async fn run(mut self, mut event_receiver: UnboundedReceiver<WatchEvent>) {
+        while let Some(first_event) = event_receiver.recv().await {
+            let mut changed_paths = HashSet::new();
+            let mut invalidate_all = false;
+
+            let deadline = Instant::now() + self.debounce * MAX_DEBOUNCE_PERIODS;
+            let mut next_event = Some(first_event);
+
+            while let Some(event) = next_event {
+                match event {
+                    // the backend lost track of changes, so nothing cached can be trusted
+                    Ok(event) if event.need_rescan() => invalidate_all = true,
+                    Ok(Event {
+                        kind: EventKind::Access(_),
+                        ..
+                    }) => {}
+                    Ok(event) => changed_paths.extend(event.paths),
+                    Err(err) => {
+                        invalidate_all = true;
+                        self.handle_error(err);
+                    }
+                }
+
+                let quiet_until = deadline.min(Instant::now() + self.debounce);
+                next_event = timeout_at(quiet_until, event_receiver.recv())
+                    .await
+                    .ok()
+                    .flatten();
+            }
+
+            if invalidate_all {
+                Cache::clear(Arc::clone(&self.cache)).await;
+            } else {
+                let changed_paths: Vec<PathBuf> = changed_paths.into_iter().collect();
+                Cache::invalidate_paths(Arc::clone(&self.cache), &changed_paths).await;
+            }
+        }
+    }
+
+    /// switches to polling when the os runs out of watches, otherwise just reports the error
+    fn handle_error(&mut self, err: notify::Error) {
+        if !matches!(err.kind, ErrorKind::MaxFilesWatch) {
+            error!(root = %self.root.display(), %err, "Error watching files");
+            return;
+        }
+
+        match start_poll_watcher(&self.root, self.event_sender.clone(), self.poll_interval) {
+            Ok(poll_watcher) => {
+                warn!("File watch limit reached, polling for changes instead");
+                self.watcher = poll_watcher;
+            }
+            Err(err) => error!(root = %self.root.display(), %err, "Could not poll for changes"),
+        }
+    }
+}
+
+/// starts the platform's native watcher (inotify, FSEvents, ...)
+fn start_native_watcher(
+    root: &Path,
+    event_sender: UnboundedSender<WatchEvent>,
+) -> notify::Result<Box<dyn Watcher + Send>> {
+    let mut watcher = RecommendedWatcher::new(
+        move |event| {
+            let _ = event_sender.send(event);
+        },
+        NotifyConfig::default(),
+    )?;
+    watcher.watch(root, RecursiveMode::Recursive)?;
+    Ok(Box::new(watcher))
+}
+
+/// starts a watcher that periodically compares file modification times
+fn start_poll_watcher(
+    root: &Path,
+    event_sender: UnboundedSender<WatchEvent>,
+    poll_interval: Duration,
+) -> notify::Result<Box<dyn Watcher + Send>> {
+    let mut watcher = PollWatcher::new(
+        move |event| {
+            let _ = event_sender.send(event);
+        },
+        NotifyConfig::default().with_poll_interval(poll_interval),
+    )?;
+    watcher.watch(root, RecursiveMode::Recursive)?;
+    Ok(Box::new(watcher))
+}
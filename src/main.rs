@@ -1,66 +1,1413 @@
 use std::convert::Infallible;
+use std::fmt::Debug;
+use std::future::Future;
+use std::io::IsTerminal;
 use std::net::SocketAddr;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use http_body_util::Full;
-use hyper::body::Bytes;
+use hyper::header::{HeaderName, HeaderValue};
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
-use hyper::{Request, Response};
-use hyper_util::rt::TokioIo;
-use tokio::net::TcpListener;
+use hyper::{Request, Response, Version};
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
+use tokio_util::task::TaskTracker;
+use tracing::level_filters::LevelFilter;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use tracing_subscriber::EnvFilter;
 
+use crate::access_log::{AccessLogger, AccessRecord};
 use crate::cache::Cache;
+use crate::config::{Config, Http2Config};
+use crate::method_handlers::handler_utils::response_body::ResponseBody;
 use crate::method_handlers::*;
+use crate::metrics::Metrics;
+use crate::resource_getters::{dir_accessor, resource_key};
+use crate::sendfile::{SendfileQueue, SendfileStream};
 
+mod access_log;
 mod cache;
+mod config;
+mod file_watcher;
 mod method_handlers;
+mod metrics;
+mod request_id;
 mod resource_getters;
+mod sendfile;
+mod tls;
+
+/// time a client gets to complete the tls handshake before the connection is dropped
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// environment variable holding the log filter, e.g. "debug" or "info,web_server::cache=debug"
+const LOG_FILTER_ENV: &str = "WEB_SERVER_LOG";
+
+/// time the access log writer gets to flush its remaining lines on shutdown
+const ACCESS_LOG_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    // def address/port and bind them
-    let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
-    let listener = TcpListener::bind(addr).await?;
+    // log to stderr at info unless the filter says otherwise, invalid directives are ignored
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::builder()
+                .with_default_directive(LevelFilter::INFO.into())
+                .with_env_var(LOG_FILTER_ENV)
+                .from_env_lossy(),
+        )
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal())
+        .init();
+
+    // load config, refusing to start if it is invalid
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            error!(%err, "Invalid configuration");
+            std::process::exit(1);
+        }
+    };
+
+    // bind every configured address before accepting on any of them
+    let mut listeners = Vec::new();
+    for addr in &config.server.listen {
+        listeners.push((TcpListener::bind(addr).await?, ListenerKind::Plain));
+    }
+    for addr in &config.redirect.listen {
+        listeners.push((TcpListener::bind(addr).await?, ListenerKind::Redirect));
+    }
+    for addr in &config.admin.listen {
+        listeners.push((TcpListener::bind(addr).await?, ListenerKind::Admin));
+    }
+
+    if !config.tls.listen.is_empty() {
+        let (tls_acceptor, certificate_resolver) =
+            match tls::build_acceptor(&config.tls, config.http2.enabled) {
+                Ok(built) => built,
+                Err(err) => {
+                    error!(%err, "Invalid TLS configuration");
+                    std::process::exit(1);
+                }
+            };
+        if config.tls.reload {
+            tls::spawn_certificate_watcher(certificate_resolver, &config.tls)?;
+        }
+        for addr in &config.tls.listen {
+            listeners.push((
+                TcpListener::bind(addr).await?,
+                ListenerKind::Tls(tls_acceptor.clone()),
+            ));
+        }
+    }
 
     // define cache to store http contents without file accesses
-    let cache = Cache::new();
+    let cache = Cache::new(&config.cache);
+
+    // evict cached resources when they change on disk, in every site's root
+    if config.watch.enabled {
+        for root in config.document_roots() {
+            file_watcher::spawn_file_watcher(
+                Arc::clone(&cache),
+                root.to_path_buf(),
+                &config.watch,
+            )?;
+        }
+    }
+
+    // uploads interrupted by a crash or restart leave staging files behind
+    if config.write.enabled {
+        for root in config.document_roots() {
+            dir_accessor::spawn_staging_cleanup(root.to_path_buf());
+        }
+    }
+
+    // the upload quota is checked against a running total, counted from disk once
+    if config.write.enabled
+        && config.write.upload.endpoint.is_some()
+        && config.write.upload.quota_bytes.is_some()
+    {
+        post_handler::seed_upload_usage(&config.write.upload).await?;
+    }
+
+    // access log lines are written by a separate task so requests never wait on the disk
+    let mut access_log = None;
+    if config.access_log.enabled {
+        match access_log::spawn_access_logger(&config.access_log).await {
+            Ok(spawned) => access_log = Some(spawned),
+            Err(err) => {
+                error!(%err, "Could not open access log");
+                std::process::exit(1);
+            }
+        }
+    }
+    let (access_logger, access_log_writer) = access_log.unzip();
+
+    // metrics are only collected when there is an admin listener to read them from
+    let metrics = (!config.admin.listen.is_empty()).then(|| Metrics::new(Arc::clone(&cache)));
+
+    // streamed files on plaintext connections are sent by the kernel where it's supported
+    let sendfile = config.server.sendfile && sendfile::available().await;
+
+    // tracks open connections and tells them when to shut down, so they can be drained
+    let connections = TaskTracker::new();
+    let (shutdown_sender, shutdown_receiver) = watch::channel(());
+
+    // one accepting loop per listener
+    let mut accept_loops = JoinSet::new();
+    for (listener, listener_kind) in listeners {
+        accept_loops.spawn(accept_connections(
+            listener,
+            listener_kind,
+            SharedState {
+                cache: Arc::clone(&cache),
+                config: Arc::clone(&config),
+                access_logger: access_logger.clone(),
+                metrics: metrics.clone(),
+                connections: connections.clone(),
+                sendfile,
+            },
+            connections.clone(),
+            shutdown_receiver.clone(),
+        ));
+    }
+
+    // serve until asked to stop, or until a listener fails
+    let mut serve_result: Result<(), Box<dyn std::error::Error + Send + Sync>> = Ok(());
+    tokio::select! {
+        _ = shutdown_signal() => info!("Shutdown signal received, draining connections"),
+        Some(result) = accept_loops.join_next() => {
+            serve_result = result
+                .map_err(Into::into)
+                .and_then(|accept_result| accept_result.map_err(Into::into));
+        }
+    }
+
+    // stop accepting, then let in-flight requests finish while idle connections close
+    accept_loops.shutdown().await;
+    connections.close();
+    let _ = shutdown_sender.send(());
+
+    let drain_timeout = Duration::from_secs(config.server.drain_timeout_secs);
+    if tokio::time::timeout(drain_timeout, connections.wait())
+        .await
+        .is_err()
+    {
+        warn!("Drain deadline reached, closing remaining connections");
+    }
+
+    // the writer finishes once the last logger is dropped, flushing what is still queued
+    drop(access_logger);
+    if let Some(access_log_writer) = access_log_writer {
+        let _ = tokio::time::timeout(ACCESS_LOG_FLUSH_TIMEOUT, access_log_writer).await;
+    }
+
+    serve_result
+}
+
+/// what a listener does with the connections it accepts
+#[derive(Clone)]
+enum ListenerKind {
+    Plain,
+    Tls(TlsAcceptor),
+    Redirect,
+    Admin,
+}
+
+impl ListenerKind {
+    /// name recorded on the spans of the connections it accepts
+    fn name(&self) -> &'static str {
+        match self {
+            ListenerKind::Plain => "plain",
+            ListenerKind::Tls(_) => "tls",
+            ListenerKind::Redirect => "redirect",
+            ListenerKind::Admin => "admin",
+        }
+    }
+}
+
+/// how requests on a connection are handled, decided by the listener that accepted it
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ConnectionRole {
+    Site,
+    Redirect,
+    Admin,
+}
+
+/// serves the protocols a listener's connections may speak
+enum ConnectionBuilder {
+    // http/1.1 and prior-knowledge h2c
+    Auto(auto::Builder<TokioExecutor>),
+    Http1(http1::Builder),
+}
+
+/// state shared by every connection
+#[derive(Clone)]
+struct SharedState {
+    cache: Arc<Cache>,
+    config: Arc<Config>,
+    access_logger: Option<AccessLogger>,
+    metrics: Option<Arc<Metrics>>,
+    // CONNECT tunnels are tracked with the connections, so they are drained on shutdown too
+    connections: TaskTracker,
+    sendfile: bool,
+}
+
+/// resolves once SIGINT or SIGTERM is received
+async fn shutdown_signal() {
+    let interrupt = async {
+        if tokio::signal::ctrl_c().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(_) => std::future::pending::<()>().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
+}
+
+/// connection accepting loop for a single listener, doing the tls handshake first on tls listeners
+async fn accept_connections(
+    listener: TcpListener,
+    listener_kind: ListenerKind,
+    shared_state: SharedState,
+    connections: TaskTracker,
+    shutdown: watch::Receiver<()>,
+) -> Result<(), std::io::Error> {
+    let builder = Arc::new(connection_builder(&shared_state.config.http2));
 
-    // connection accepting loop
     loop {
-        let (stream, _) = listener.accept().await?;
-        let io = TokioIo::new(stream);
-        let cache_clone = Arc::clone(&cache);
+        let (stream, peer_addr) = listener.accept().await?;
+        let builder_clone = Arc::clone(&builder);
+        let shared_state_clone = shared_state.clone();
+        let shutdown_clone = shutdown.clone();
+        let listener_kind_clone = listener_kind.clone();
+        let connection_span =
+            info_span!("connection", peer = %peer_addr, listener = listener_kind.name());
 
         // spawns tokio task for concurrent handling
-        tokio::task::spawn(async move {
-            if let Err(err) = http1::Builder::new()
-                .serve_connection(
-                    io,
-                    service_fn(|req| handle_conn(req, Arc::clone(&cache_clone))),
+        connections.spawn(
+            async move {
+                let tls_acceptor = match listener_kind_clone {
+                    ListenerKind::Tls(tls_acceptor) => tls_acceptor,
+                    ListenerKind::Plain | ListenerKind::Redirect | ListenerKind::Admin => {
+                        let role = match listener_kind_clone {
+                            ListenerKind::Redirect => ConnectionRole::Redirect,
+                            ListenerKind::Admin => ConnectionRole::Admin,
+                            _ => ConnectionRole::Site,
+                        };
+                        return serve_plain_connection(
+                            stream,
+                            &builder_clone,
+                            shared_state_clone,
+                            peer_addr,
+                            shutdown_clone,
+                            role,
+                        )
+                        .await;
+                    }
+                };
+
+                match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls_acceptor.accept(stream)).await
+                {
+                    Ok(Ok(tls_stream)) => {
+                        serve_connection(
+                            TokioIo::new(tls_stream),
+                            &builder_clone,
+                            shared_state_clone,
+                            peer_addr,
+                            shutdown_clone,
+                            ConnectionRole::Site,
+                            None,
+                        )
+                        .await
+                    }
+                    Ok(Err(err)) => warn!(%err, "TLS handshake failed"),
+                    Err(_) => warn!("TLS handshake timed out"),
+                }
+            }
+            .instrument(connection_span),
+        );
+    }
+}
+
+/// serves a plaintext connection, site connections sending streamed files with sendfile where it's enabled
+async fn serve_plain_connection(
+    stream: TcpStream,
+    builder: &ConnectionBuilder,
+    shared_state: SharedState,
+    peer_addr: SocketAddr,
+    shutdown: watch::Receiver<()>,
+    role: ConnectionRole,
+) {
+    if role == ConnectionRole::Site && shared_state.sendfile {
+        let queue = SendfileQueue::default();
+        let stream = SendfileStream::new(stream, queue.clone());
+        serve_connection(
+            TokioIo::new(stream),
+            builder,
+            shared_state,
+            peer_addr,
+            shutdown,
+            role,
+            Some(queue),
+        )
+        .await
+    } else {
+        serve_connection(
+            TokioIo::new(stream),
+            builder,
+            shared_state,
+            peer_addr,
+            shutdown,
+            role,
+            None,
+        )
+        .await
+    }
+}
+
+/// serves one connection, switching to a graceful shutdown (finish in-flight requests, close idle) when signalled.
+/// Upgrades are allowed so CONNECT can hand the connection over to a tunnel
+async fn serve_connection<I>(
+    io: TokioIo<I>,
+    builder: &ConnectionBuilder,
+    shared_state: SharedState,
+    peer_addr: SocketAddr,
+    shutdown: watch::Receiver<()>,
+    role: ConnectionRole,
+    sendfile: Option<SendfileQueue>,
+) where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // admin connections aren't counted, so scraping doesn't show up in the metrics it reads
+    let _connection_guard = match &shared_state.metrics {
+        Some(metrics) if role != ConnectionRole::Admin => Some(metrics.connection_opened()),
+        _ => None,
+    };
+
+    let service = service_fn(move |mut req: Request<hyper::body::Incoming>| {
+        // h2 frames body data itself, so only http/1 responses can have files sent in their place
+        if let Some(queue) = &sendfile {
+            if matches!(req.version(), Version::HTTP_10 | Version::HTTP_11) {
+                req.extensions_mut().insert(queue.clone());
+            }
+        }
+        let shared_state = shared_state.clone();
+        let request_id = request_id::from_request(&req);
+        let request_span = info_span!(
+            "request",
+            id = %request_id,
+            method = %req.method(),
+            path = %req.uri().path(),
+        );
+
+        async move {
+            let mut response = serve_request(req, shared_state, peer_addr, role).await?;
+            debug!(status = response.status().as_u16(), "Response ready");
+
+            // echo the id so clients can match their request to the server's logs
+            if let Ok(request_id) = HeaderValue::from_str(&request_id) {
+                response
+                    .headers_mut()
+                    .insert(request_id::X_REQUEST_ID, request_id);
+            }
+            Ok::<_, Infallible>(response)
+        }
+        .instrument(request_span)
+    });
+
+    match builder {
+        ConnectionBuilder::Auto(builder) => {
+            drive_connection(
+                builder.serve_connection_with_upgrades(io, service),
+                shutdown,
+                |connection| connection.graceful_shutdown(),
+            )
+            .await
+        }
+        ConnectionBuilder::Http1(builder) => {
+            drive_connection(
+                builder.serve_connection(io, service).with_upgrades(),
+                shutdown,
+                |connection| connection.graceful_shutdown(),
+            )
+            .await
+        }
+    }
+}
+
+/// runs a connection to the end, starting its graceful shutdown when signalled
+async fn drive_connection<C, E>(
+    connection: C,
+    mut shutdown: watch::Receiver<()>,
+    graceful_shutdown: impl FnOnce(Pin<&mut C>),
+) where
+    C: Future<Output = Result<(), E>>,
+    E: Debug,
+{
+    tokio::pin!(connection);
+
+    let result = tokio::select! {
+        result = connection.as_mut() => result,
+        _ = shutdown.changed() => {
+            graceful_shutdown(connection.as_mut());
+            connection.await
+        }
+    };
+
+    if let Err(err) = result {
+        warn!(?err, "Error serving connection");
+    }
+}
+
+/// routes a request by the connection's role, recording it for the metrics and access log
+async fn serve_request(
+    req: Request<hyper::body::Incoming>,
+    shared_state: SharedState,
+    peer_addr: SocketAddr,
+    role: ConnectionRole,
+) -> Result<Response<ResponseBody>, Infallible> {
+    if role == ConnectionRole::Admin {
+        return match &shared_state.metrics {
+            Some(metrics) => {
+                metrics_handler::handle_admin(&req, metrics, &shared_state.config.admin).await
+            }
+            None => handler_utils::packet_templates::send_error_packet(),
+        };
+    }
+
+    // the request is recorded before the handler takes it, and logged once the body is sent
+    let started = Instant::now();
+    let method = req.method().clone();
+    let access_record = shared_state
+        .access_logger
+        .is_some()
+        .then(|| AccessRecord::from_request(&req, peer_addr));
+
+    let mut response = handle_conn(
+        req,
+        Arc::clone(&shared_state.cache),
+        Arc::clone(&shared_state.config),
+        role == ConnectionRole::Redirect,
+        &shared_state.connections,
+    )
+    .await?;
+
+    if let Some(metrics) = &shared_state.metrics {
+        response = metrics.observe_response(&method, started, response);
+    }
+    if let (Some(logger), Some(record)) = (&shared_state.access_logger, access_record) {
+        response = logger.log_response(record, response);
+    }
+    Ok(response)
+}
+
+/// builds a connection builder serving http/1.1 and, when enabled, prior-knowledge h2c
+fn connection_builder(http2_config: &Http2Config) -> ConnectionBuilder {
+    // the auto builder ignores http1_only when serving with upgrades, so http/1.1 gets its own
+    if !http2_config.enabled {
+        return ConnectionBuilder::Http1(http1::Builder::new());
+    }
+
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    builder
+        .http2()
+        .timer(TokioTimer::new())
+        .max_concurrent_streams(http2_config.max_concurrent_streams)
+        .initial_stream_window_size(http2_config.initial_stream_window_size)
+        .initial_connection_window_size(http2_config.initial_connection_window_size)
+        .adaptive_window(http2_config.adaptive_window)
+        .keep_alive_interval(
+            http2_config
+                .keep_alive_interval_secs
+                .map(Duration::from_secs),
+        )
+        .keep_alive_timeout(Duration::from_secs(http2_config.keep_alive_timeout_secs));
+    ConnectionBuilder::Auto(builder)
+}
+
+async fn handle_conn(
+    req: Request<hyper::body::Incoming>,
+    cache_ref: Arc<Cache>,
+    config_ref: Arc<Config>,
+    redirect: bool,
+    connections: &TaskTracker,
+) -> Result<Response<ResponseBody>, Infallible> {
+    // on redirect listeners only whitelisted paths are served directly
+    if redirect && !redirect_handler::is_passthrough(&req, &config_ref.redirect) {
+        return redirect_handler::handle_redirect(&req, &config_ref.redirect);
+    }
+
+    // tunnels don't belong to a site, so no site's headers are added
+    if req.method() == hyper::Method::CONNECT {
+        return connect_handler::handle_connect(req, &config_ref.proxy, connections).await;
+    }
+
+    // pick the site from the Host header
+    let site = config_ref.select_site(&resource_key::normalise_host(&req));
+
+    // check request type
+    let mut response = match *req.method() {
+        hyper::Method::OPTIONS => options_handler::handle_option(req, &config_ref).await,
+        hyper::Method::GET => {
+            get_handler::handle_get(req, Arc::clone(&cache_ref), &site, &config_ref).await
+        }
+        hyper::Method::HEAD => {
+            head_handler::handle_head(req, Arc::clone(&cache_ref), &site, &config_ref).await
+        }
+        hyper::Method::POST => {
+            post_handler::handle_post(req, Arc::clone(&cache_ref), &config_ref).await
+        }
+        hyper::Method::PUT => {
+            put_handler::handle_put(req, Arc::clone(&cache_ref), &site, &config_ref).await
+        }
+        hyper::Method::DELETE => {
+            delete_handler::handle_delete(req, Arc::clone(&cache_ref), &site, &config_ref).await
+        }
+        hyper::Method::TRACE => trace_handler::handle_trace(req, &config_ref.trace).await,
+        _ => handler_utils::packet_templates::send_not_implemented_packet(),
+    }?;
+
+    // add the site's configured headers, validated when the config was loaded
+    for (name, value) in &site.resources.headers {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            response.headers_mut().insert(name, value);
+        }
+    }
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use http_body_util::{BodyExt, Empty, Full};
+    use hyper::body::Bytes;
+    use hyper::client::conn::{http1, http2};
+    use hyper::header::{
+        ACCEPT, ACCEPT_ENCODING, AUTHORIZATION, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE,
+        CONTENT_TYPE, ETAG, HOST, IF_MATCH, IF_NONE_MATCH, LOCATION, RANGE,
+    };
+    use hyper::{Method, StatusCode, Version};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use super::*;
+
+    const CONTENT: &str = "hello over every protocol";
+
+    /// a document root with one small file and the 404 page the config requires
+    fn document_root() -> tempfile::TempDir {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("404.html"), "not found").unwrap();
+        std::fs::write(root.path().join("hello.txt"), CONTENT).unwrap();
+        root
+    }
+
+    fn site_config(root: &Path, extra: &str) -> Arc<Config> {
+        Config::from_toml(&format!(
+            "[server]\nlisten = [\"127.0.0.1:0\"]\n[resources]\nroot = {:?}\n{}",
+            root, extra
+        ))
+        .unwrap()
+    }
+
+    /// serves a single site connection on an ephemeral port, returning the address to connect to
+    async fn serve_one_connection(config: Arc<Config>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (stream, peer_addr) = listener.accept().await.unwrap();
+            let builder = connection_builder(&config.http2);
+            let shared_state = SharedState {
+                cache: Cache::new(&config.cache),
+                config: Arc::clone(&config),
+                access_logger: None,
+                metrics: None,
+                connections: TaskTracker::new(),
+                sendfile: config.server.sendfile && sendfile::available().await,
+            };
+            // kept alive for the whole connection, a dropped sender would start a shutdown
+            let (_shutdown_sender, shutdown_receiver) = watch::channel(());
+            serve_plain_connection(
+                stream,
+                &builder,
+                shared_state,
+                peer_addr,
+                shutdown_receiver,
+                ConnectionRole::Site,
+            )
+            .await;
+        });
+        addr
+    }
+
+    /// a client connection speaking one protocol
+    enum Client {
+        Http1(http1::SendRequest<Full<Bytes>>),
+        Http2(http2::SendRequest<Full<Bytes>>),
+    }
+
+    impl Client {
+        async fn connect(addr: SocketAddr, version: Version) -> Self {
+            let io = TokioIo::new(TcpStream::connect(addr).await.unwrap());
+            if version == Version::HTTP_2 {
+                let (sender, connection) =
+                    http2::handshake(TokioExecutor::new(), io).await.unwrap();
+                tokio::spawn(connection);
+                Client::Http2(sender)
+            } else {
+                let (sender, connection) = http1::handshake(io).await.unwrap();
+                tokio::spawn(connection);
+                Client::Http1(sender)
+            }
+        }
+
+        async fn send(
+            &mut self,
+            method: Method,
+            path: &str,
+            headers: &[(HeaderName, &str)],
+        ) -> (hyper::http::response::Parts, Bytes) {
+            self.send_with_body(method, path, headers, Bytes::new())
+                .await
+        }
+
+        async fn send_with_body(
+            &mut self,
+            method: Method,
+            path: &str,
+            headers: &[(HeaderName, &str)],
+            body: impl Into<Bytes>,
+        ) -> (hyper::http::response::Parts, Bytes) {
+            let mut builder = Request::builder().method(method);
+            builder = match self {
+                Client::Http1(_) => builder.uri(path).header(HOST, "localhost"),
+                Client::Http2(_) => builder.uri(format!("http://localhost{}", path)),
+            };
+            for (name, value) in headers {
+                builder = builder.header(name, *value);
+            }
+            let req = builder.body(Full::new(body.into())).unwrap();
+
+            let response = match self {
+                Client::Http1(sender) => sender.send_request(req).await.unwrap(),
+                Client::Http2(sender) => sender.send_request(req).await.unwrap(),
+            };
+            let (parts, body) = response.into_parts();
+            (parts, body.collect().await.unwrap().to_bytes())
+        }
+    }
+
+    /// GET, HEAD, range and conditional requests over one connection of the given protocol
+    async fn check_protocol(version: Version) {
+        let root = document_root();
+        let addr = serve_one_connection(site_config(root.path(), "")).await;
+        let mut client = Client::connect(addr, version).await;
+
+        let (parts, body) = client.send(Method::GET, "/hello.txt", &[]).await;
+        assert_eq!(parts.version, version);
+        assert_eq!(parts.status, StatusCode::OK);
+        assert_eq!(body, CONTENT);
+        let etag = parts.headers[ETAG].to_str().unwrap().to_string();
+
+        let (parts, body) = client.send(Method::HEAD, "/hello.txt", &[]).await;
+        assert_eq!(parts.status, StatusCode::OK);
+        assert_eq!(parts.headers[CONTENT_LENGTH], CONTENT.len().to_string());
+        assert!(body.is_empty());
+
+        let (parts, body) = client
+            .send(Method::GET, "/hello.txt", &[(RANGE, "bytes=0-4")])
+            .await;
+        assert_eq!(parts.status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            parts.headers[CONTENT_RANGE],
+            format!("bytes 0-4/{}", CONTENT.len())
+        );
+        assert_eq!(body, "hello");
+
+        let (parts, body) = client
+            .send(Method::GET, "/hello.txt", &[(IF_NONE_MATCH, &etag)])
+            .await;
+        assert_eq!(parts.status, StatusCode::NOT_MODIFIED);
+        assert!(body.is_empty());
+
+        let (parts, _) = client.send(Method::GET, "/missing.txt", &[]).await;
+        assert_eq!(parts.status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn serves_http1() {
+        check_protocol(Version::HTTP_11).await;
+    }
+
+    #[tokio::test]
+    async fn serves_prior_knowledge_h2c() {
+        check_protocol(Version::HTTP_2).await;
+    }
+
+    #[tokio::test]
+    async fn refuses_h2c_when_http2_is_disabled() {
+        let root = document_root();
+        let config = site_config(root.path(), "[http2]\nenabled = false\n");
+        let addr = serve_one_connection(config).await;
+
+        let io = TokioIo::new(TcpStream::connect(addr).await.unwrap());
+        let (mut sender, connection) = http2::handshake(TokioExecutor::new(), io).await.unwrap();
+        tokio::spawn(connection);
+        let req = Request::builder()
+            .uri("http://localhost/hello.txt")
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+        assert!(sender.send_request(req).await.is_err());
+    }
+
+    const WRITE_TOKEN: (HeaderName, &str) = (AUTHORIZATION, "Bearer secret");
+
+    /// a site accepting writes of up to 64 bytes, listing hidden files so staging files would show
+    fn writable_site() -> (tempfile::TempDir, Arc<Config>) {
+        let root = document_root();
+        let config = site_config(
+            root.path(),
+            "[resources.autoindex]\nenabled = true\nshow_hidden = true\n\
+             [write]\nenabled = true\ntokens = [\"secret\"]\nmax_bytes = 64\n",
+        );
+        (root, config)
+    }
+
+    #[tokio::test]
+    async fn puts_new_and_replaced_files() {
+        let (root, config) = writable_site();
+        let addr = serve_one_connection(config).await;
+        let mut client = Client::connect(addr, Version::HTTP_11).await;
+
+        let (parts, _) = client
+            .send_with_body(Method::PUT, "/new%20file.txt", &[], "first")
+            .await;
+        assert_eq!(parts.status, StatusCode::UNAUTHORIZED);
+
+        let (parts, _) = client
+            .send_with_body(Method::PUT, "/new%20file.txt", &[WRITE_TOKEN], "first")
+            .await;
+        assert_eq!(parts.status, StatusCode::CREATED);
+        assert_eq!(parts.headers[LOCATION], "/new%20file.txt");
+        let (_, body) = client.send(Method::GET, "/new%20file.txt", &[]).await;
+        assert_eq!(body, "first");
+
+        let (parts, _) = client
+            .send_with_body(Method::PUT, "/new%20file.txt", &[WRITE_TOKEN], "second")
+            .await;
+        assert_eq!(parts.status, StatusCode::NO_CONTENT);
+        let (_, body) = client.send(Method::GET, "/new%20file.txt", &[]).await;
+        assert_eq!(body, "second");
+        assert_eq!(
+            std::fs::read(root.path().join("new file.txt")).unwrap(),
+            b"second"
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_puts_failing_preconditions_or_too_large() {
+        let (root, config) = writable_site();
+        let addr = serve_one_connection(config).await;
+        let mut client = Client::connect(addr, Version::HTTP_11).await;
+
+        let (parts, _) = client
+            .send_with_body(
+                Method::PUT,
+                "/hello.txt",
+                &[WRITE_TOKEN, (IF_NONE_MATCH, "*")],
+                "replaced",
+            )
+            .await;
+        assert_eq!(parts.status, StatusCode::PRECONDITION_FAILED);
+
+        let (parts, _) = client
+            .send_with_body(
+                Method::PUT,
+                "/hello.txt",
+                &[WRITE_TOKEN, (IF_MATCH, "\"stale\"")],
+                "replaced",
+            )
+            .await;
+        assert_eq!(parts.status, StatusCode::PRECONDITION_FAILED);
+
+        let (parts, _) = client
+            .send_with_body(Method::PUT, "/hello.txt", &[WRITE_TOKEN], vec![b'x'; 65])
+            .await;
+        assert_eq!(parts.status, StatusCode::PAYLOAD_TOO_LARGE);
+
+        // the current version passes If-Match
+        let (parts, _) = client.send(Method::GET, "/hello.txt", &[]).await;
+        let etag = parts.headers[ETAG].to_str().unwrap().to_string();
+        let (parts, _) = client
+            .send_with_body(
+                Method::PUT,
+                "/hello.txt",
+                &[WRITE_TOKEN, (IF_MATCH, &etag)],
+                "replaced",
+            )
+            .await;
+        assert_eq!(parts.status, StatusCode::NO_CONTENT);
+
+        // nothing is left behind by the refused uploads
+        let mut names: Vec<_> = std::fs::read_dir(root.path())
+            .unwrap()
+            .map(|dir_entry| dir_entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["404.html", "hello.txt"]);
+    }
+
+    #[tokio::test]
+    async fn hides_upload_staging_files() {
+        let (root, config) = writable_site();
+        let staging_name = ".hello.txt.0123456789abcdef0123456789abcdef.tmp";
+        std::fs::write(root.path().join(staging_name), "partial").unwrap();
+        std::fs::write(root.path().join(".visible"), "hidden but listed").unwrap();
+        let addr = serve_one_connection(config).await;
+        let mut client = Client::connect(addr, Version::HTTP_11).await;
+
+        let (parts, _) = client
+            .send(Method::GET, &format!("/{}", staging_name), &[])
+            .await;
+        assert_eq!(parts.status, StatusCode::NOT_FOUND);
+
+        let (parts, body) = client
+            .send(Method::GET, "/", &[(ACCEPT, "text/html")])
+            .await;
+        assert_eq!(parts.status, StatusCode::OK);
+        let listing = String::from_utf8(body.to_vec()).unwrap();
+        assert!(listing.contains(".visible"));
+        assert!(!listing.contains(staging_name));
+
+        let (parts, _) = client
+            .send_with_body(
+                Method::PUT,
+                &format!("/{}", staging_name),
+                &[WRITE_TOKEN],
+                "x",
+            )
+            .await;
+        assert_eq!(parts.status, StatusCode::FORBIDDEN);
+        let (parts, _) = client
+            .send(
+                Method::DELETE,
+                &format!("/{}", staging_name),
+                &[WRITE_TOKEN],
+            )
+            .await;
+        assert_eq!(parts.status, StatusCode::FORBIDDEN);
+        assert!(root.path().join(staging_name).exists());
+    }
+
+    /// a site with an upload endpoint storing files in its uploads directory
+    fn upload_site(extra: &str) -> (tempfile::TempDir, Arc<Config>) {
+        let root = document_root();
+        std::fs::create_dir(root.path().join("uploads")).unwrap();
+        let config = site_config(
+            root.path(),
+            &format!(
+                "[write]\nenabled = true\ntokens = [\"secret\"]\nmax_bytes = 64\n\
+                 [write.upload]\nendpoint = \"/upload\"\ndirectory = {:?}\n{}",
+                root.path().join("uploads"),
+                extra
+            ),
+        );
+        (root, config)
+    }
+
+    /// a multipart form of (name, file name, value) parts
+    fn multipart_form(parts: &[(&str, Option<&str>, &str)]) -> String {
+        let mut form = String::new();
+        for (name, file_name, value) in parts {
+            form.push_str("--BOUNDARY\r\n");
+            match file_name {
+                Some(file_name) => form.push_str(&format!(
+                    "Content-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\n\
+                     Content-Type: text/plain\r\n\r\n",
+                    name, file_name
+                )),
+                None => form.push_str(&format!(
+                    "Content-Disposition: form-data; name=\"{}\"\r\n\r\n",
+                    name
+                )),
+            }
+            form.push_str(value);
+            form.push_str("\r\n");
+        }
+        form.push_str("--BOUNDARY--\r\n");
+        form
+    }
+
+    const MULTIPART: (HeaderName, &str) = (CONTENT_TYPE, "multipart/form-data; boundary=BOUNDARY");
+
+    #[tokio::test]
+    async fn stores_multipart_files_under_sanitised_names() {
+        let (root, config) = upload_site("");
+        let addr = serve_one_connection(config).await;
+        let mut client = Client::connect(addr, Version::HTTP_11).await;
+        let form = multipart_form(&[
+            ("note", None, "kept as a field"),
+            ("first", Some("../../escape me.txt"), "first file"),
+            ("second", Some("escape me.txt"), "second file"),
+            ("third", Some("..hidden"), "third file"),
+        ]);
+
+        let (parts, body) = client
+            .send_with_body(Method::POST, "/upload", &[MULTIPART], form.clone())
+            .await;
+        assert_eq!(parts.status, StatusCode::UNAUTHORIZED);
+        assert!(body.is_empty());
+
+        let (parts, body) = client
+            .send_with_body(Method::POST, "/upload", &[WRITE_TOKEN, MULTIPART], form)
+            .await;
+        assert_eq!(parts.status, StatusCode::CREATED);
+        let summary: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            summary["fields"],
+            serde_json::json!([{ "name": "note", "value": "kept as a field" }])
+        );
+        let stored: Vec<_> = summary["files"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|file| {
+                (
+                    file["filename"].as_str().unwrap(),
+                    file["stored_as"].as_str().unwrap(),
+                    file["size"].as_u64().unwrap(),
                 )
+            })
+            .collect();
+        assert_eq!(
+            stored,
+            [
+                ("../../escape me.txt", "escape_me.txt", 10),
+                ("escape me.txt", "escape_me-1.txt", 11),
+                ("..hidden", "hidden", 10),
+            ]
+        );
+
+        let uploads = root.path().join("uploads");
+        assert_eq!(
+            std::fs::read(uploads.join("escape_me.txt")).unwrap(),
+            b"first file"
+        );
+        assert_eq!(
+            std::fs::read(uploads.join("escape_me-1.txt")).unwrap(),
+            b"second file"
+        );
+        assert_eq!(std::fs::read_dir(&uploads).unwrap().count(), 3);
+    }
+
+    #[tokio::test]
+    async fn summarises_urlencoded_forms() {
+        let (root, config) = upload_site("");
+        let addr = serve_one_connection(config).await;
+        let mut client = Client::connect(addr, Version::HTTP_11).await;
+
+        let (parts, body) = client
+            .send_with_body(
+                Method::POST,
+                "/upload",
+                &[
+                    WRITE_TOKEN,
+                    (CONTENT_TYPE, "application/x-www-form-urlencoded"),
+                ],
+                "name=a+b&empty=&name=%3Cc%3E",
+            )
+            .await;
+        assert_eq!(parts.status, StatusCode::OK);
+        let summary: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            summary,
+            serde_json::json!({
+                "files": [],
+                "fields": [
+                    { "name": "name", "value": "a b" },
+                    { "name": "empty", "value": "" },
+                    { "name": "name", "value": "<c>" },
+                ],
+            })
+        );
+        assert_eq!(
+            std::fs::read_dir(root.path().join("uploads"))
+                .unwrap()
+                .count(),
+            0
+        );
+
+        let (parts, _) = client
+            .send_with_body(
+                Method::POST,
+                "/upload",
+                &[WRITE_TOKEN, (CONTENT_TYPE, "text/plain")],
+                "name=value",
+            )
+            .await;
+        assert_eq!(parts.status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let (parts, _) = client
+            .send_with_body(
+                Method::POST,
+                "/elsewhere",
+                &[
+                    WRITE_TOKEN,
+                    (CONTENT_TYPE, "application/x-www-form-urlencoded"),
+                ],
+                "name=value",
+            )
+            .await;
+        assert_eq!(parts.status, StatusCode::NOT_IMPLEMENTED);
+    }
+
+    /// the only test with a quota, as the usage it is checked against is shared by the process
+    #[tokio::test]
+    async fn keeps_uploads_within_the_quota() {
+        let (root, config) = upload_site("quota_bytes = 32\n");
+        let uploads = root.path().join("uploads");
+        std::fs::write(uploads.join("existing.txt"), [b'x'; 20]).unwrap();
+        post_handler::seed_upload_usage(&config.write.upload)
+            .await
+            .unwrap();
+        let addr = serve_one_connection(config).await;
+        let mut client = Client::connect(addr, Version::HTTP_11).await;
+        let upload =
+            |file_name: &str, value: &str| multipart_form(&[("file", Some(file_name), value)]);
+
+        let (parts, _) = client
+            .send_with_body(
+                Method::POST,
+                "/upload",
+                &[WRITE_TOKEN, MULTIPART],
+                upload("first.txt", "0123456789"),
+            )
+            .await;
+        assert_eq!(parts.status, StatusCode::CREATED);
+
+        // 30 of 32 bytes are used, and a refused form keeps none of its files
+        let (parts, _) = client
+            .send_with_body(
+                Method::POST,
+                "/upload",
+                &[WRITE_TOKEN, MULTIPART],
+                multipart_form(&[
+                    ("file", Some("small.txt"), "1"),
+                    ("file", Some("large.txt"), "0123"),
+                ]),
+            )
+            .await;
+        assert_eq!(parts.status, StatusCode::INSUFFICIENT_STORAGE);
+        assert!(!uploads.join("small.txt").exists());
+
+        // deleting a file frees its share of the quota
+        let (parts, _) = client
+            .send(Method::DELETE, "/uploads/existing.txt", &[WRITE_TOKEN])
+            .await;
+        assert_eq!(parts.status, StatusCode::NO_CONTENT);
+        let (parts, _) = client
+            .send_with_body(
+                Method::POST,
+                "/upload",
+                &[WRITE_TOKEN, MULTIPART],
+                upload("large.txt", "01234567890123456789"),
+            )
+            .await;
+        assert_eq!(parts.status, StatusCode::CREATED);
+
+        // as does a PUT replacing a stored file with a smaller one
+        let (parts, _) = client
+            .send_with_body(Method::PUT, "/uploads/large.txt", &[WRITE_TOKEN], "0")
+            .await;
+        assert_eq!(parts.status, StatusCode::NO_CONTENT);
+        let (parts, _) = client
+            .send_with_body(
+                Method::POST,
+                "/upload",
+                &[WRITE_TOKEN, MULTIPART],
+                upload("last.txt", "012345678901234567890"),
+            )
+            .await;
+        assert_eq!(parts.status, StatusCode::CREATED);
+        assert_eq!(
+            handler_utils::write_access::WRITE_LOCK
+                .lock()
                 .await
-            {
-                eprintln!("Error serving connection: {:?}", err);
+                .upload_usage,
+            32
+        );
+    }
+
+    /// numbered lines spanning several placeholder chunks, so misplaced bytes show up
+    fn large_content() -> Vec<u8> {
+        (0..30_000)
+            .flat_map(|line| format!("{:08}\n", line).into_bytes())
+            .collect()
+    }
+
+    /// a site streaming files over a kilobyte from disk, holding a large file and a gzipped stand-in for it
+    fn streaming_site() -> (tempfile::TempDir, Arc<Config>) {
+        let root = document_root();
+        std::fs::write(root.path().join("large.txt"), large_content()).unwrap();
+        std::fs::write(root.path().join("large.txt.gz"), &large_content()[..50_000]).unwrap();
+        let config = site_config(
+            root.path(),
+            "stream_threshold_bytes = 1024\nprecompressed = true\n",
+        );
+        (root, config)
+    }
+
+    /// full, single range, multipart and precompressed responses for a streamed file
+    async fn check_streamed_file(version: Version) {
+        let (_root, config) = streaming_site();
+        let addr = serve_one_connection(config).await;
+        let mut client = Client::connect(addr, version).await;
+        let content = large_content();
+
+        let (parts, body) = client.send(Method::GET, "/large.txt", &[]).await;
+        assert_eq!(parts.status, StatusCode::OK);
+        assert_eq!(parts.headers[CONTENT_LENGTH], content.len().to_string());
+        assert_eq!(body, content);
+
+        let (parts, body) = client
+            .send(Method::GET, "/large.txt", &[(RANGE, "bytes=60000-140000")])
+            .await;
+        assert_eq!(parts.status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(body, content[60000..=140000]);
+
+        let (parts, body) = client
+            .send(Method::GET, "/large.txt", &[(RANGE, "bytes=-100")])
+            .await;
+        assert_eq!(parts.status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            parts.headers[CONTENT_RANGE],
+            format!(
+                "bytes {}-{}/{}",
+                content.len() - 100,
+                content.len() - 1,
+                content.len()
+            )
+        );
+        assert_eq!(body, content[content.len() - 100..]);
+
+        let (parts, body) = client
+            .send(
+                Method::GET,
+                "/large.txt",
+                &[(RANGE, "bytes=0-8,90000-90008")],
+            )
+            .await;
+        assert_eq!(parts.status, StatusCode::PARTIAL_CONTENT);
+        assert!(parts.headers[CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("multipart/byteranges"));
+        for line in [&b"00000000\n"[..], &b"00010000\n"[..]] {
+            assert!(body.windows(line.len()).any(|window| window == line));
+        }
+
+        let (parts, body) = client
+            .send(Method::GET, "/large.txt", &[(ACCEPT_ENCODING, "gzip")])
+            .await;
+        assert_eq!(parts.status, StatusCode::OK);
+        assert_eq!(parts.headers[CONTENT_ENCODING], "gzip");
+        assert_eq!(body, content[..50_000]);
+
+        // an in-memory body on the same connection still goes out as it is
+        let (_, body) = client.send(Method::GET, "/hello.txt", &[]).await;
+        assert_eq!(body, CONTENT);
+    }
+
+    #[tokio::test]
+    async fn streams_files_with_sendfile_over_http1() {
+        check_streamed_file(Version::HTTP_11).await;
+    }
+
+    #[tokio::test]
+    async fn streams_files_over_h2c() {
+        check_streamed_file(Version::HTTP_2).await;
+    }
+
+    /// the head and body of the first http/1.1 response in output, and whatever follows it
+    fn split_response(output: &[u8]) -> (String, &[u8], &[u8]) {
+        let head_end = output
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .unwrap()
+            + 4;
+        let head = String::from_utf8(output[..head_end].to_vec()).unwrap();
+        let length: usize = head
+            .lines()
+            .find_map(|line| line.strip_prefix("content-length: "))
+            .unwrap()
+            .parse()
+            .unwrap();
+        let (body, rest) = output[head_end..].split_at(length);
+        (head, body, rest)
+    }
+
+    #[tokio::test]
+    async fn sends_pipelined_files_in_order() {
+        let (_root, config) = streaming_site();
+        let addr = serve_one_connection(config).await;
+        let content = large_content();
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                b"GET /large.txt HTTP/1.1\r\nHost: localhost\r\n\r\n\
+                  GET /large.txt HTTP/1.1\r\nHost: localhost\r\nRange: bytes=100000-200000\r\n\r\n\
+                  GET /hello.txt HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut output = Vec::new();
+        stream.read_to_end(&mut output).await.unwrap();
+
+        let (head, body, rest) = split_response(&output);
+        assert!(head.starts_with("HTTP/1.1 200"));
+        assert_eq!(body, content);
+        let (head, body, rest) = split_response(rest);
+        assert!(head.starts_with("HTTP/1.1 206"));
+        assert_eq!(body, &content[100000..=200000]);
+        let (head, body, rest) = split_response(rest);
+        assert!(head.starts_with("HTTP/1.1 200"));
+        assert_eq!(body, CONTENT.as_bytes());
+        assert!(rest.is_empty());
+    }
+
+    /// echoes back everything written to it, on an ephemeral port
+    async fn spawn_echo_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = stream.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
             }
         });
+        addr
     }
 
-    async fn handle_conn(
-        req: Request<hyper::body::Incoming>,
-        cache_ref: Arc<Cache>,
-    ) -> Result<Response<Full<Bytes>>, Infallible> {
-        // check request type
-        return match *req.method() {
-            hyper::Method::OPTIONS => options_handler::handle_option(req).await,
-            hyper::Method::GET => get_handler::handle_get(req, Arc::clone(&cache_ref)).await,
-            hyper::Method::HEAD => head_handler::handle_head(req, Arc::clone(&cache_ref)).await,
-            hyper::Method::POST => post_handler::handle_post(req).await,
-            hyper::Method::PUT => put_handler::handle_put(req).await,
-            hyper::Method::DELETE => delete_handler::handle_delete(req).await,
-            hyper::Method::TRACE => trace_handler::handle_trace(req).await,
-            hyper::Method::CONNECT => connect_handler::handle_connect(req).await,
-            _ => handler_utils::packet_templates::send_not_implemented_packet(),
-        };
+    /// sends a CONNECT for the target over a fresh http/1.1 connection
+    async fn send_connect(
+        proxy_addr: SocketAddr,
+        target: &str,
+        credentials: Option<&str>,
+    ) -> Response<hyper::body::Incoming> {
+        let io = TokioIo::new(TcpStream::connect(proxy_addr).await.unwrap());
+        let (mut sender, connection) = http1::handshake(io).await.unwrap();
+        tokio::spawn(connection.with_upgrades());
+
+        let mut builder = Request::builder()
+            .method(Method::CONNECT)
+            .uri(target)
+            .header(HOST, target);
+        if let Some(credentials) = credentials {
+            builder = builder.header(
+                hyper::header::PROXY_AUTHORIZATION,
+                format!("Basic {}", STANDARD.encode(credentials)),
+            );
+        }
+        sender
+            .send_request(builder.body(Empty::<Bytes>::new()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn tunnels_connect_to_allowed_destinations() {
+        let echo_addr = spawn_echo_server().await;
+        let root = document_root();
+        let config = site_config(
+            root.path(),
+            &format!(
+                "headers = {{ X-Site = \"yes\" }}\n\
+                 [proxy]\nenabled = true\ncredentials = [\"alice:secret\"]\n\
+                 allow = [\"127.0.0.1:{}\"]\n",
+                echo_addr.port()
+            ),
+        );
+        let target = echo_addr.to_string();
+
+        let response = send_connect(
+            serve_one_connection(Arc::clone(&config)).await,
+            &target,
+            Some("alice:secret"),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!response.headers().contains_key("x-site"));
+
+        let mut tunnel = TokioIo::new(hyper::upgrade::on(response).await.unwrap());
+        tunnel.write_all(b"through the tunnel").await.unwrap();
+        let mut echoed = [0; 18];
+        tunnel.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"through the tunnel");
+
+        let response = send_connect(
+            serve_one_connection(Arc::clone(&config)).await,
+            &target,
+            Some("alice:wrong"),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::PROXY_AUTHENTICATION_REQUIRED);
+
+        let response = send_connect(
+            serve_one_connection(Arc::clone(&config)).await,
+            &target,
+            None,
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::PROXY_AUTHENTICATION_REQUIRED);
+
+        // only the port given is allowed
+        let response = send_connect(
+            serve_one_connection(Arc::clone(&config)).await,
+            &format!("127.0.0.1:{}", echo_addr.port().wrapping_add(1)),
+            Some("alice:secret"),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn refuses_connect_when_the_proxy_is_disabled() {
+        let echo_addr = spawn_echo_server().await;
+        let root = document_root();
+        let addr = serve_one_connection(site_config(root.path(), "")).await;
+
+        let response = send_connect(addr, &echo_addr.to_string(), Some("alice:secret")).await;
+        assert_eq!(response.status(), StatusCode::NOT_IMPLEMENTED);
     }
 }
@@ -1,16 +1,117 @@
 use std::convert::Infallible;
+use std::time::Duration;
 
-use http_body_util::Full;
-use hyper::body::Bytes;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hyper::header::PROXY_AUTHORIZATION;
 use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use tokio::io;
+use tokio::net::TcpStream;
+use tokio::time;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, info, warn, Instrument};
 
-// Handles option requests, returning either a option response packet or server error packet
+use crate::config::ProxyConfig;
+use crate::method_handlers::handler_utils;
+use crate::method_handlers::handler_utils::response_body::{self, ResponseBody};
+use crate::method_handlers::handler_utils::write_access;
+
+// Handles connect requests, tunnelling to an allowed destination once the client is authenticated
 pub(crate) async fn handle_connect(
-    _req: Request<hyper::body::Incoming>,
-) -> Result<Response<Full<Bytes>>, Infallible> {
+    req: Request<hyper::body::Incoming>,
+    proxy_config: &ProxyConfig,
+    tunnels: &TaskTracker,
+) -> Result<Response<ResponseBody>, Infallible> {
+    if !proxy_config.enabled {
+        return handler_utils::packet_templates::send_not_implemented_packet();
+    }
+    if !is_authorized(&req, proxy_config) {
+        return handler_utils::packet_templates::send_proxy_authentication_required_packet();
+    }
+
+    // CONNECT targets are always host:port
+    let (host, port) = match req.uri().authority() {
+        Some(authority) => match authority.port_u16() {
+            Some(port) => (authority.host().to_ascii_lowercase(), port),
+            None => return handler_utils::packet_templates::send_bad_request_packet(),
+        },
+        None => return handler_utils::packet_templates::send_bad_request_packet(),
+    };
+    if !is_allowed(&host, port, proxy_config) {
+        return handler_utils::packet_templates::send_forbidden_packet();
+    }
+
+    // connect before answering, so the client is told if the destination can't be reached
+    let address = (host.trim_start_matches('[').trim_end_matches(']'), port);
+    let timeout = Duration::from_secs(proxy_config.connect_timeout_secs);
+    let mut upstream = match time::timeout(timeout, TcpStream::connect(address)).await {
+        Ok(Ok(upstream)) => upstream,
+        Ok(Err(err)) => {
+            warn!(%err, %host, port, "Could not connect to tunnel destination");
+            return handler_utils::packet_templates::send_bad_gateway_packet();
+        }
+        Err(_) => {
+            warn!(%host, port, "Connecting to tunnel destination timed out");
+            return handler_utils::packet_templates::send_gateway_timeout_packet();
+        }
+    };
+
+    // the connection is handed over once the 200 has been sent
+    tunnels.spawn(
+        async move {
+            let upgraded = match hyper::upgrade::on(req).await {
+                Ok(upgraded) => upgraded,
+                Err(err) => {
+                    warn!(%err, "Could not upgrade connection for tunnel");
+                    return;
+                }
+            };
+
+            info!("Tunnel opened");
+            match io::copy_bidirectional(&mut TokioIo::new(upgraded), &mut upstream).await {
+                Ok((sent, received)) => info!(sent, received, "Tunnel closed"),
+                Err(err) => debug!(%err, "Tunnel closed with error"),
+            }
+        }
+        .instrument(tracing::info_span!("tunnel", %host, port)),
+    );
+
     let response = Response::builder()
-        .status(StatusCode::NOT_IMPLEMENTED)
-        .body(Full::new(Bytes::new()))
+        .status(StatusCode::OK)
+        .body(response_body::empty())
         .unwrap();
     Ok(response)
 }
+
+/// true if the request carries one of the configured user:password pairs as Basic credentials
+fn is_authorized<B>(req: &Request<B>, proxy_config: &ProxyConfig) -> bool {
+    let credentials = match req
+        .headers()
+        .get(PROXY_AUTHORIZATION)
+        .and_then(|authorization| authorization.to_str().ok())
+        .and_then(|authorization| authorization.strip_prefix("Basic "))
+        .and_then(|encoded| STANDARD.decode(encoded.trim()).ok())
+    {
+        Some(credentials) => credentials,
+        None => return false,
+    };
+
+    proxy_config
+        .credentials
+        .iter()
+        .any(|configured| write_access::constant_time_eq(configured.as_bytes(), &credentials))
+}
+
+/// true if host:port matches an allow entry, names being compared rather than resolved addresses
+fn is_allowed(host: &str, port: u16, proxy_config: &ProxyConfig) -> bool {
+    proxy_config
+        .allow
+        .iter()
+        .any(|destination| match destination.rsplit_once(':') {
+            Some((allowed_host, allowed_port)) => {
+                allowed_host == host && (allowed_port == "*" || allowed_port.parse() == Ok(port))
+            }
+            None => false,
+        })
+}
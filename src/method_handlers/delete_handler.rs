@@ -1,16 +1,104 @@
 use std::convert::Infallible;
+use std::io::ErrorKind;
+use std::sync::Arc;
 
-use http_body_util::Full;
-use hyper::body::Bytes;
-use hyper::{Request, Response, StatusCode};
+use hyper::{Request, Response};
+use tokio::fs;
+use tracing::error;
 
-// Handles option requests, returning either a option response packet or server error packet
+use crate::cache::Cache;
+use crate::config::{Config, Site};
+use crate::method_handlers::handler_utils;
+use crate::method_handlers::handler_utils::response_body::ResponseBody;
+use crate::method_handlers::handler_utils::write_access;
+use crate::resource_getters::dir_accessor;
+use crate::resource_getters::path_sanitiser::PathError;
+
+// Handles delete requests, removing a file (or an empty directory if allowed) and returning 204, 404 or 409
 pub(crate) async fn handle_delete(
-    _req: Request<hyper::body::Incoming>,
-) -> Result<Response<Full<Bytes>>, Infallible> {
-    let response = Response::builder()
-        .status(StatusCode::NOT_IMPLEMENTED)
-        .body(Full::new(Bytes::new()))
-        .unwrap();
-    Ok(response)
+    req: Request<hyper::body::Incoming>,
+    cache: Arc<Cache>,
+    site: &Site<'_>,
+    config: &Config,
+) -> Result<Response<ResponseBody>, Infallible> {
+    if !config.write.enabled {
+        return handler_utils::packet_templates::send_not_implemented_packet();
+    }
+    if !write_access::is_authorized(&req, &config.write) {
+        return handler_utils::packet_templates::send_unauthorized_packet();
+    }
+
+    let (path, request_path) = match write_access::resolve_target(&req, site) {
+        Ok(Some(target)) => target,
+        Ok(None) => return send_not_found(site).await,
+        Err(PathError::Malformed) => {
+            return handler_utils::packet_templates::send_bad_request_packet()
+        }
+        Err(PathError::Forbidden) => {
+            return handler_utils::packet_templates::send_forbidden_packet()
+        }
+    };
+
+    // preconditions are checked and the target removed without another write in between
+    let mut write_state = write_access::WRITE_LOCK.lock().await;
+
+    // the target itself, not what a symlink points to
+    let metadata = match fs::symlink_metadata(&path).await {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == ErrorKind::NotFound => return send_not_found(site).await,
+        Err(err) => {
+            error!(%err, path = %path.display(), "Could not read file to delete");
+            return handler_utils::packet_templates::send_error_packet();
+        }
+    };
+    let is_dir = metadata.is_dir();
+    if !is_dir && request_path.ends_with('/') {
+        return send_not_found(site).await;
+    }
+    if is_dir && !config.write.delete_directories {
+        return handler_utils::packet_templates::send_conflict_packet();
+    }
+
+    let current_version = if is_dir {
+        None
+    } else {
+        write_access::current_version(&path, site.resources).await
+    };
+    if !write_access::preconditions_hold(req.headers(), current_version.as_ref()) {
+        return handler_utils::packet_templates::send_precondition_failed_packet();
+    }
+
+    let removed = if is_dir {
+        fs::remove_dir(&path).await
+    } else {
+        fs::remove_file(&path).await
+    };
+    if removed.is_ok() && metadata.is_file() {
+        write_state.resize_upload(&config.write.upload, &path, metadata.len(), 0);
+    }
+    drop(write_state);
+
+    match removed {
+        Ok(()) => {}
+        Err(err) if err.kind() == ErrorKind::DirectoryNotEmpty => {
+            return handler_utils::packet_templates::send_conflict_packet()
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => return send_not_found(site).await,
+        Err(err) => {
+            error!(%err, path = %path.display(), "Could not delete");
+            return handler_utils::packet_templates::send_error_packet();
+        }
+    }
+
+    Cache::invalidate_paths(Arc::clone(&cache), &[path]).await;
+    handler_utils::packet_templates::send_no_content_packet()
+}
+
+/// 404 with the site's not found page, as a GET for the path would get
+async fn send_not_found(site: &Site<'_>) -> Result<Response<ResponseBody>, Infallible> {
+    handler_utils::packet_templates::send_not_found_packet(
+        dir_accessor::retrieve_not_found_page(site.resources)
+            .await
+            .unwrap_or_default(),
+    )
 }
@@ -1,7 +1,6 @@
 use std::time::SystemTime;
 
 use chrono::{DateTime, Duration, Utc};
-use hyper::body::Bytes;
 use hyper::header::HeaderValue;
 use hyper::HeaderMap;
 
@@ -58,10 +57,7 @@ pub(crate) fn if_range(
             };
 
             // Convert the date header to a date
-            let date_val = match header_to_date(date_val_header) {
-                Some(date) => date,
-                None => return None,
-            };
+            let date_val = header_to_date(date_val_header)?;
 
             // Compare the dates with a 1-second tolerance
             let resource_mod_time: DateTime<Utc> = DateTime::from(*modified_since);
@@ -80,20 +76,14 @@ pub(crate) fn if_range(
     }
 }
 
-/// returns bytes of the requested range
-pub(crate) fn range(content: &Bytes, range_header: &HeaderValue) -> Option<Vec<(Bytes, u64, u64)>> {
-    // content length for checking ranges
-    let content_length = content.len() as u64;
-
+/// returns the inclusive start and end of each requested range
+pub(crate) fn range(content_length: u64, range_header: &HeaderValue) -> Option<Vec<(u64, u64)>> {
     let range_str = match range_header.to_str() {
         Ok(range_str) => range_str,
         Err(_) => return None,
     };
 
-    let stripped_str = match range_str.strip_prefix("bytes=") {
-        Some(stripped_str) => stripped_str,
-        None => return None,
-    };
+    let stripped_str = range_str.strip_prefix("bytes=")?;
 
     let range_pairs: Vec<&str> = stripped_str.split(',').collect();
     let mut ranges: Vec<(u64, u64)> = Vec::new();
@@ -139,16 +129,17 @@ pub(crate) fn range(content: &Bytes, range_header: &HeaderValue) -> Option<Vec<(
         return None;
     }
 
-    let mut sliced_content: Vec<(Bytes, u64, u64)> = Vec::new();
-    // if in ascending order and there is not more than 1 overlap, slice content
-    for &(start, end) in ranges.iter() {
-        sliced_content.push((slice_with_range(start, end, content)?, start, end))
-    }
-    Some(sliced_content)
+    // in ascending order and not overlapping more than twice
+    Some(ranges)
 }
 
 /// if range is valid, return range start and end in u64
 fn try_get_range(range_slice: &[&str], content_length: u64) -> Option<(u64, u64)> {
+    // an empty representation has no satisfiable range, and every range is a single x-y pair
+    if content_length == 0 || range_slice.len() != 2 {
+        return None;
+    }
+
     match (range_slice[0].is_empty(), range_slice[1].is_empty()) {
         (false, false) => {
             // range x-y
@@ -188,31 +179,19 @@ fn try_get_range(range_slice: &[&str], content_length: u64) -> Option<(u64, u64)
                 Err(_) => return None,
             };
 
-            if from_end < content_length {
-                Some(((content_length - 1) - from_end, content_length - 1))
-            } else {
-                Some((0, content_length - 1))
+            // the last y bytes, or the whole representation if it is shorter
+            match from_end {
+                0 => None,
+                from_end if from_end < content_length => {
+                    Some((content_length - from_end, content_length - 1))
+                }
+                _ => Some((0, content_length - 1)),
             }
         }
         _ => None,
     }
 }
 
-/// slices content according to range
-fn slice_with_range(start: u64, end: u64, content: &Bytes) -> Option<Bytes> {
-    let start_index = match usize::try_from(start) {
-        Ok(index) => index,
-        Err(_) => return None,
-    };
-
-    let end_index = match usize::try_from(end) {
-        Ok(index) => index,
-        Err(_) => return None,
-    };
-
-    Some(content.slice(start_index..end_index + 1))
-}
-
 /// returns true if according to http spec the cache can be checked based on request headers
 pub(crate) fn can_check_cache(header_value: &HeaderMap) -> bool {
     if header_value.get("If-Match").is_some() || header_value.get("If-Unmodified-Since").is_some() {
@@ -270,3 +249,50 @@ fn compare_etag_vec(header_slice: &[&str], resource_etag: &str) -> bool {
         .iter()
         .any(|&etag| etag == "*" || etag == resource_etag)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(content_length: u64, header: &'static str) -> Option<Vec<(u64, u64)>> {
+        range(content_length, &HeaderValue::from_static(header))
+    }
+
+    #[test]
+    fn reads_start_end_and_open_ended_ranges() {
+        assert_eq!(ranges(100, "bytes=0-9"), Some(vec![(0, 9)]));
+        assert_eq!(ranges(100, "bytes=90-"), Some(vec![(90, 99)]));
+        assert_eq!(ranges(100, "bytes=0-0,50-59"), Some(vec![(0, 0), (50, 59)]));
+        assert_eq!(ranges(100, "bytes=10-100"), None);
+        assert_eq!(ranges(100, "bytes=9-0"), None);
+    }
+
+    #[test]
+    fn suffix_ranges_cover_the_last_bytes() {
+        assert_eq!(ranges(100, "bytes=-10"), Some(vec![(90, 99)]));
+        assert_eq!(ranges(100, "bytes=-1"), Some(vec![(99, 99)]));
+        assert_eq!(ranges(100, "bytes=-100"), Some(vec![(0, 99)]));
+        assert_eq!(ranges(100, "bytes=-500"), Some(vec![(0, 99)]));
+        assert_eq!(ranges(100, "bytes=-0"), None);
+    }
+
+    #[test]
+    fn rejects_ranges_without_a_single_dash() {
+        for header in [
+            "bytes=5",
+            "bytes=",
+            "bytes=1-2-3",
+            "bytes=0-1,5",
+            "bytes=--5",
+        ] {
+            assert_eq!(ranges(100, header), None, "{}", header);
+        }
+    }
+
+    #[test]
+    fn rejects_ranges_of_empty_representations() {
+        for header in ["bytes=0-0", "bytes=0-", "bytes=-1"] {
+            assert_eq!(ranges(0, header), None, "{}", header);
+        }
+    }
+}
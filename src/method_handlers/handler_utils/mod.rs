@@ -1,2 +1,4 @@
 pub mod header_evals;
 pub mod packet_templates;
+pub mod response_body;
+pub mod write_access;
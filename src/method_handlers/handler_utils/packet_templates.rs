@@ -2,47 +2,57 @@ use std::convert::Infallible;
 use std::time::SystemTime;
 
 use chrono::{DateTime, Days, Utc};
-use http_body_util::Full;
+use futures_util::{future, stream, StreamExt};
 use hyper::body::Bytes;
 use hyper::header::{
     ACCEPT_RANGES, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, DATE, ETAG, EXPIRES,
-    LAST_MODIFIED, SERVER,
+    LAST_MODIFIED, LOCATION, PROXY_AUTHENTICATE, SERVER, WWW_AUTHENTICATE,
 };
 use hyper::{Response, StatusCode};
 
+use crate::config::HandlerConfig;
+use crate::method_handlers::handler_utils::response_body::{self, ChunkStream, ResponseBody};
+
 /// sends ok packet
 pub(crate) fn send_default_ok_packet(
-    resource_content: Bytes,
+    resource_content: ResponseBody,
+    content_length: u64,
     content_type: &str,
     last_modified: SystemTime,
     etag: &str,
-) -> Result<Response<Full<Bytes>>, Infallible> {
+    handler_config: &HandlerConfig,
+) -> Result<Response<ResponseBody>, Infallible> {
     let response = Response::builder()
         .status(StatusCode::OK)
         .header(DATE, get_current_http_date())
         .header(CONTENT_TYPE, content_type)
-        .header(CONTENT_LENGTH, resource_content.len())
+        .header(CONTENT_LENGTH, content_length)
         .header(LAST_MODIFIED, system_time_to_http_date(&last_modified))
-        .header(EXPIRES, get_http_expiry_date())
+        .header(EXPIRES, get_http_expiry_date(handler_config.expiry_days))
         .header(ETAG, etag)
         .header(ACCEPT_RANGES, "bytes")
-        .header(CACHE_CONTROL, "max-age=36000")
-        .header(SERVER, "ZACHARY-RUST-SERVER")
-        .body(Full::new(resource_content))
+        .header(
+            CACHE_CONTROL,
+            format!("max-age={}", handler_config.cache_max_age),
+        )
+        .header(SERVER, &handler_config.server_name)
+        .body(resource_content)
         .unwrap();
     Ok(response)
 }
 
 /// sends partial content packet (where there is only 1 part)
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn send_partial_content_packet(
-    data_slice: Bytes,
+    data_slice: ChunkStream,
     slice_start: &u64,
     slice_end: &u64,
-    original_length: &usize,
+    original_length: &u64,
     content_type: &str,
     last_modified: &SystemTime,
     etag: &str,
-) -> Result<Response<Full<Bytes>>, Infallible> {
+    handler_config: &HandlerConfig,
+) -> Result<Response<ResponseBody>, Infallible> {
     let content_range = format!("bytes {}-{}/{}", slice_start, slice_end, original_length);
 
     let response = Response::builder()
@@ -50,45 +60,51 @@ pub(crate) fn send_partial_content_packet(
         .header(DATE, get_current_http_date())
         .header(CONTENT_TYPE, content_type)
         .header(CONTENT_RANGE, content_range)
-        .header(CONTENT_LENGTH, data_slice.len())
+        .header(CONTENT_LENGTH, slice_end - slice_start + 1)
         .header(LAST_MODIFIED, system_time_to_http_date(last_modified))
-        .header(EXPIRES, get_http_expiry_date())
+        .header(EXPIRES, get_http_expiry_date(handler_config.expiry_days))
         .header(ETAG, etag)
         .header(ACCEPT_RANGES, "bytes")
-        .header(CACHE_CONTROL, "max-age=36000")
-        .header(SERVER, "ZACHARY-RUST-SERVER")
-        .body(Full::new(data_slice))
+        .header(
+            CACHE_CONTROL,
+            format!("max-age={}", handler_config.cache_max_age),
+        )
+        .header(SERVER, &handler_config.server_name)
+        .body(response_body::from_chunks(data_slice))
         .unwrap();
     Ok(response)
 }
 
 /// sends partial content packet (where there are several parts)
 pub(crate) fn send_multipart_packet(
-    ranges_vector: Vec<(Bytes, u64, u64)>,
-    original_length: &usize,
+    ranges_vector: Vec<(ChunkStream, u64, u64)>,
+    original_length: &u64,
     content_type: &str,
     last_modified: &SystemTime,
     etag: &str,
-) -> Result<Response<Full<Bytes>>, Infallible> {
+    handler_config: &HandlerConfig,
+) -> Result<Response<ResponseBody>, Infallible> {
     let boundary = "BOUNDARY";
 
-    let mut multipart_body = Vec::new();
+    let mut multipart_body: Vec<ChunkStream> = Vec::new();
+    let mut body_length = 0;
 
+    // the part headers are small, the slices themselves are streamed
     for (slice, start, end) in ranges_vector {
-        multipart_body.push(
-            format!(
-                "--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
-                boundary, content_type, start, end, original_length
-            )
-            .into_bytes(),
+        let part_header = format!(
+            "--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+            boundary, content_type, start, end, original_length
         );
-        multipart_body.push(slice.to_vec());
-        multipart_body.push(b"\r\n".to_vec());
+        body_length += part_header.len() as u64 + (end - start + 1) + 2;
+        multipart_body.push(stream::once(future::ok(Bytes::from(part_header))).boxed());
+        multipart_body.push(slice);
+        multipart_body.push(stream::once(future::ok(Bytes::from_static(b"\r\n"))).boxed());
     }
-    multipart_body.push(format!("--{}--\r\n", boundary).into_bytes());
+    let closing_boundary = format!("--{}--\r\n", boundary);
+    body_length += closing_boundary.len() as u64;
+    multipart_body.push(stream::once(future::ok(Bytes::from(closing_boundary))).boxed());
 
-    // Flatten the body into a single Vec<u8>
-    let body: Vec<u8> = multipart_body.into_iter().flatten().collect();
+    let body = stream::iter(multipart_body).flatten().boxed();
 
     // Create the response
     let response = Response::builder()
@@ -98,59 +114,189 @@ pub(crate) fn send_multipart_packet(
             CONTENT_TYPE,
             format!("multipart/byteranges; boundary={}", boundary),
         )
-        .header(CONTENT_LENGTH, body.len())
+        .header(CONTENT_LENGTH, body_length)
         .header(LAST_MODIFIED, system_time_to_http_date(last_modified))
-        .header(EXPIRES, get_http_expiry_date())
+        .header(EXPIRES, get_http_expiry_date(handler_config.expiry_days))
         .header(ETAG, etag)
         .header(ACCEPT_RANGES, "bytes")
-        .header(CACHE_CONTROL, "max-age=36000")
-        .header(SERVER, "ZACHARY-RUST-SERVER")
-        .body(Full::from(Bytes::from(body)))
+        .header(
+            CACHE_CONTROL,
+            format!("max-age={}", handler_config.cache_max_age),
+        )
+        .header(SERVER, &handler_config.server_name)
+        .body(response_body::from_chunks(body))
         .unwrap();
     Ok(response)
 }
 
 /// sends 404 not found packet
-pub(crate) fn send_not_found_packet(data: Bytes) -> Result<Response<Full<Bytes>>, Infallible> {
+pub(crate) fn send_not_found_packet(data: Bytes) -> Result<Response<ResponseBody>, Infallible> {
     let response = Response::builder()
         .status(StatusCode::NOT_FOUND)
-        .body(Full::new(data))
+        .body(response_body::full(data))
+        .unwrap();
+    Ok(response)
+}
+
+/// sends a redirect packet pointing at location
+pub(crate) fn send_redirect_packet(
+    status: StatusCode,
+    location: &str,
+) -> Result<Response<ResponseBody>, Infallible> {
+    let response = Response::builder()
+        .status(status)
+        .header(LOCATION, location)
+        .header(CONTENT_LENGTH, 0)
+        .body(response_body::empty())
+        .unwrap();
+    Ok(response)
+}
+
+/// sends bad request packet
+pub(crate) fn send_bad_request_packet() -> Result<Response<ResponseBody>, Infallible> {
+    let response = Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .body(response_body::empty())
+        .unwrap();
+    Ok(response)
+}
+
+/// sends forbidden packet
+pub(crate) fn send_forbidden_packet() -> Result<Response<ResponseBody>, Infallible> {
+    let response = Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .body(response_body::empty())
+        .unwrap();
+    Ok(response)
+}
+
+/// sends an unauthorized packet asking for a bearer token
+pub(crate) fn send_unauthorized_packet() -> Result<Response<ResponseBody>, Infallible> {
+    let response = Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header(WWW_AUTHENTICATE, "Bearer realm=\"web_server\"")
+        .body(response_body::empty())
+        .unwrap();
+    Ok(response)
+}
+
+/// sends a proxy authentication required packet asking for basic credentials
+pub(crate) fn send_proxy_authentication_required_packet(
+) -> Result<Response<ResponseBody>, Infallible> {
+    let response = Response::builder()
+        .status(StatusCode::PROXY_AUTHENTICATION_REQUIRED)
+        .header(PROXY_AUTHENTICATE, "Basic realm=\"web_server\"")
+        .body(response_body::empty())
+        .unwrap();
+    Ok(response)
+}
+
+/// sends a conflict packet, for writes the state of the document root doesn't allow
+pub(crate) fn send_conflict_packet() -> Result<Response<ResponseBody>, Infallible> {
+    let response = Response::builder()
+        .status(StatusCode::CONFLICT)
+        .body(response_body::empty())
+        .unwrap();
+    Ok(response)
+}
+
+/// sends a payload too large packet
+pub(crate) fn send_payload_too_large_packet() -> Result<Response<ResponseBody>, Infallible> {
+    let response = Response::builder()
+        .status(StatusCode::PAYLOAD_TOO_LARGE)
+        .body(response_body::empty())
+        .unwrap();
+    Ok(response)
+}
+
+/// sends an unsupported media type packet
+pub(crate) fn send_unsupported_media_type_packet() -> Result<Response<ResponseBody>, Infallible> {
+    let response = Response::builder()
+        .status(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+        .body(response_body::empty())
+        .unwrap();
+    Ok(response)
+}
+
+/// sends an insufficient storage packet, for writes that would exceed a quota
+pub(crate) fn send_insufficient_storage_packet() -> Result<Response<ResponseBody>, Infallible> {
+    let response = Response::builder()
+        .status(StatusCode::INSUFFICIENT_STORAGE)
+        .body(response_body::empty())
+        .unwrap();
+    Ok(response)
+}
+
+/// sends a created packet pointing at the new resource
+pub(crate) fn send_created_packet(location: &str) -> Result<Response<ResponseBody>, Infallible> {
+    let response = Response::builder()
+        .status(StatusCode::CREATED)
+        .header(LOCATION, location)
+        .header(CONTENT_LENGTH, 0)
+        .body(response_body::empty())
+        .unwrap();
+    Ok(response)
+}
+
+/// sends a no content packet
+pub(crate) fn send_no_content_packet() -> Result<Response<ResponseBody>, Infallible> {
+    let response = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(response_body::empty())
         .unwrap();
     Ok(response)
 }
 
 /// sends internal server error packet
-pub(crate) fn send_error_packet() -> Result<Response<Full<Bytes>>, Infallible> {
+pub(crate) fn send_error_packet() -> Result<Response<ResponseBody>, Infallible> {
     let response = Response::builder()
         .status(StatusCode::INTERNAL_SERVER_ERROR)
-        .body(Full::new(Bytes::new()))
+        .body(response_body::empty())
+        .unwrap();
+    Ok(response)
+}
+
+/// sends a bad gateway packet, for upstreams that couldn't be reached
+pub(crate) fn send_bad_gateway_packet() -> Result<Response<ResponseBody>, Infallible> {
+    let response = Response::builder()
+        .status(StatusCode::BAD_GATEWAY)
+        .body(response_body::empty())
+        .unwrap();
+    Ok(response)
+}
+
+/// sends a gateway timeout packet, for upstreams that didn't answer in time
+pub(crate) fn send_gateway_timeout_packet() -> Result<Response<ResponseBody>, Infallible> {
+    let response = Response::builder()
+        .status(StatusCode::GATEWAY_TIMEOUT)
+        .body(response_body::empty())
         .unwrap();
     Ok(response)
 }
 
 /// sends not implemented packet
-pub(crate) fn send_not_implemented_packet() -> Result<Response<Full<Bytes>>, Infallible> {
+pub(crate) fn send_not_implemented_packet() -> Result<Response<ResponseBody>, Infallible> {
     let response = Response::builder()
         .status(StatusCode::NOT_IMPLEMENTED)
-        .body(Full::new(Bytes::new()))
+        .body(response_body::empty())
         .unwrap();
     Ok(response)
 }
 
 /// sends a precondition failed packet
-pub(crate) fn send_precondition_failed_packet() -> Result<Response<Full<Bytes>>, Infallible> {
+pub(crate) fn send_precondition_failed_packet() -> Result<Response<ResponseBody>, Infallible> {
     let response = Response::builder()
         .status(StatusCode::PRECONDITION_FAILED)
-        .body(Full::new(Bytes::new()))
+        .body(response_body::empty())
         .unwrap();
     Ok(response)
 }
 
 /// sends not modified packet
-pub(crate) fn send_not_modified_packet() -> Result<Response<Full<Bytes>>, Infallible> {
+pub(crate) fn send_not_modified_packet() -> Result<Response<ResponseBody>, Infallible> {
     let response = Response::builder()
         .status(StatusCode::NOT_MODIFIED)
-        .body(Full::new(Bytes::new()))
+        .body(response_body::empty())
         .unwrap();
     Ok(response)
 }
@@ -168,7 +314,7 @@ fn get_current_http_date() -> String {
 }
 
 /// gets the set expiry date in http format
-fn get_http_expiry_date() -> String {
+fn get_http_expiry_date(expiry_days: u64) -> String {
     let now: DateTime<Utc> = Utc::now();
-    (now + Days::new(4)).to_rfc2822()
+    (now + Days::new(expiry_days)).to_rfc2822()
 }
@@ -0,0 +1,111 @@
+use std::io::{self, SeekFrom};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_util::stream::{self, BoxStream};
+use futures_util::{StreamExt, TryStreamExt};
+use http_body_util::combinators::UnsyncBoxBody;
+use http_body_util::{BodyExt, Empty, Full, StreamBody};
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+
+/// size of the reads used when streaming a file
+const FILE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// body of every response, either held in memory or streamed from disk
+pub(crate) type ResponseBody = UnsyncBoxBody<Bytes, io::Error>;
+
+/// chunks of a streamed body
+pub(crate) type ChunkStream = BoxStream<'static, io::Result<Bytes>>;
+
+/// callback given the number of body bytes sent once a response is finished
+type CompletionCallback = Box<dyn FnOnce(u64) + Send>;
+
+/// body counting the bytes sent through it, calling back once it is finished or dropped
+struct CompletionBody {
+    inner: ResponseBody,
+    bytes_sent: u64,
+    on_complete: Option<CompletionCallback>,
+}
+
+/// body holding data already in memory
+pub(crate) fn full(data: Bytes) -> ResponseBody {
+    Full::new(data)
+        .map_err(|never| match never {})
+        .boxed_unsync()
+}
+
+/// body with no content
+pub(crate) fn empty() -> ResponseBody {
+    Empty::new().map_err(|never| match never {}).boxed_unsync()
+}
+
+/// body sending the chunks as they are produced
+pub(crate) fn from_chunks(chunks: ChunkStream) -> ResponseBody {
+    StreamBody::new(chunks.map_ok(Frame::data)).boxed_unsync()
+}
+
+/// streams length bytes of a file from offset, the file is only opened once the body is polled
+pub(crate) fn file_chunks(path: PathBuf, offset: u64, length: u64) -> ChunkStream {
+    stream::once(async move {
+        let mut file = File::open(&path).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        Ok::<_, io::Error>(ReaderStream::with_capacity(
+            file.take(length),
+            FILE_CHUNK_SIZE,
+        ))
+    })
+    .try_flatten()
+    .boxed()
+}
+
+/// wraps a body so on_complete is called with the bytes sent once it is finished,
+/// or abandoned because the client went away
+pub(crate) fn with_completion(
+    body: ResponseBody,
+    on_complete: impl FnOnce(u64) + Send + 'static,
+) -> ResponseBody {
+    CompletionBody {
+        inner: body,
+        bytes_sent: 0,
+        on_complete: Some(Box::new(on_complete)),
+    }
+    .boxed_unsync()
+}
+
+impl Body for CompletionBody {
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll {
+            if let Some(data) = frame.data_ref() {
+                self.bytes_sent += data.len() as u64;
+            }
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for CompletionBody {
+    fn drop(&mut self) {
+        if let Some(on_complete) = self.on_complete.take() {
+            on_complete(self.bytes_sent);
+        }
+    }
+}
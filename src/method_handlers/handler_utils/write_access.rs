@@ -0,0 +1,182 @@
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use hyper::header::{AUTHORIZATION, IF_MATCH, IF_NONE_MATCH, IF_UNMODIFIED_SINCE};
+use hyper::{HeaderMap, Request};
+use tokio::sync::Mutex;
+
+use crate::cache::Cache;
+use crate::config::{ResourceConfig, Site, UploadConfig, WriteConfig};
+use crate::method_handlers::handler_utils::header_evals;
+use crate::resource_getters::content_encoding::Encoding;
+use crate::resource_getters::dir_accessor;
+use crate::resource_getters::path_sanitiser::{self, PathError};
+use crate::resource_getters::resource_key::ResourceKey;
+use crate::resource_getters::web_content::ResourceBody;
+
+/// held while a write checks its preconditions and changes the document root,
+/// so two requests can't both pass an If-Match against the same version
+pub(crate) static WRITE_LOCK: Mutex<WriteState> = Mutex::const_new(WriteState { upload_usage: 0 });
+
+/// what writes keep track of between requests, only changed while WRITE_LOCK is held
+pub(crate) struct WriteState {
+    // bytes used by the files directly inside the upload directory, counted at startup
+    // and kept up to date by every write when there is an upload quota
+    pub(crate) upload_usage: u64,
+}
+
+impl WriteState {
+    /// accounts for a file changing size from previous to current bytes, files outside the
+    /// upload directory are ignored
+    pub(crate) fn resize_upload(
+        &mut self,
+        upload_config: &UploadConfig,
+        path: &Path,
+        previous: u64,
+        current: u64,
+    ) {
+        if upload_config.endpoint.is_none()
+            || upload_config.quota_bytes.is_none()
+            || path.parent() != Some(upload_config.directory.as_path())
+        {
+            return;
+        }
+        self.upload_usage = self
+            .upload_usage
+            .saturating_sub(previous)
+            .saturating_add(current);
+    }
+}
+
+/// true if the request carries one of the configured bearer tokens
+pub(crate) fn is_authorized<B>(req: &Request<B>, write_config: &WriteConfig) -> bool {
+    let token = match req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|authorization| authorization.to_str().ok())
+        .and_then(|authorization| authorization.strip_prefix("Bearer "))
+    {
+        Some(token) => token.trim(),
+        None => return false,
+    };
+
+    write_config
+        .tokens
+        .iter()
+        .any(|configured| constant_time_eq(configured.as_bytes(), token.as_bytes()))
+}
+
+/// file or directory a write request targets, along with its normalised request path. The parent
+/// directory is canonicalised so a symlinked directory can't place the target outside the root,
+/// and a symlink is targeted itself rather than what it points to.
+/// None if the path is the root or its parent directory doesn't exist.
+pub(crate) fn resolve_target<B>(
+    req: &Request<B>,
+    site: &Site<'_>,
+) -> Result<Option<(PathBuf, String)>, PathError> {
+    let resource_key = ResourceKey::from_request(req, site.namespace)?;
+    let request_path = resource_key.get_path();
+
+    let (parent_path, file_name) = match request_path.trim_end_matches('/').rsplit_once('/') {
+        Some((_, "")) | None => return Ok(None),
+        Some(split) => split,
+    };
+    let parent = match path_sanitiser::confine_path(&site.resources.root, parent_path)? {
+        parent if parent.is_dir() => parent,
+        _ => return Ok(None),
+    };
+
+    // uploads in progress can't be replaced or removed through their staging files
+    let path = parent.join(file_name);
+    if dir_accessor::is_staging_file(&path) {
+        return Err(PathError::Forbidden);
+    }
+
+    // an existing target is confined again, as it may itself be a symlink
+    if path.exists() {
+        path_sanitiser::confine_path(&site.resources.root, request_path)?;
+    }
+    Ok(Some((path, request_path.to_string())))
+}
+
+/// etag and modification time of a file as a GET would report them, None if there is no file
+pub(crate) async fn current_version(
+    path: &Path,
+    resource_config: &ResourceConfig,
+) -> Option<(String, SystemTime)> {
+    if !path.is_file() {
+        return None;
+    }
+
+    match dir_accessor::retrieve_resource(path, resource_config).await? {
+        (ResourceBody::InMemory(data), Some((_, last_modified))) => {
+            Some((Cache::generate_etag(&data), last_modified))
+        }
+        (ResourceBody::File { length, .. }, Some((_, last_modified))) => Some((
+            Cache::generate_file_etag(length, &last_modified),
+            last_modified,
+        )),
+        _ => None,
+    }
+}
+
+/// evaluates If-Match, If-Unmodified-Since and If-None-Match against the current version of
+/// the target, None meaning it doesn't exist. If-None-Match: * only passes when there is no file.
+/// The etags GET gives compressed responses name the same version, so they match too.
+pub(crate) fn preconditions_hold(
+    headers: &HeaderMap,
+    current_version: Option<&(String, SystemTime)>,
+) -> bool {
+    if let Some(header) = headers.get(IF_MATCH) {
+        match current_version {
+            Some((etag, _)) => {
+                if version_etags(etag)
+                    .iter()
+                    .all(|etag| header_evals::if_match(header, etag) == Some(false))
+                {
+                    return false;
+                }
+            }
+            None => return false,
+        }
+    } else if let (Some(header), Some((_, last_modified))) =
+        (headers.get(IF_UNMODIFIED_SINCE), current_version)
+    {
+        if header_evals::if_unmodified_since(header, last_modified) == Some(false) {
+            return false;
+        }
+    }
+
+    if let (Some(header), Some((etag, _))) = (headers.get(IF_NONE_MATCH), current_version) {
+        if version_etags(etag)
+            .iter()
+            .any(|etag| header_evals::if_none_match(header, etag) == Some(false))
+        {
+            return false;
+        }
+    }
+    true
+}
+
+/// the identity etag of a version followed by the etags of its compressed variants
+fn version_etags(etag: &str) -> Vec<String> {
+    let mut etags = vec![etag.to_string()];
+    etags.extend(
+        [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip]
+            .iter()
+            .map(|encoding| format!("{}-{}", etag, encoding.token())),
+    );
+    etags
+}
+
+/// compares every byte so the time taken doesn't reveal how much of a token matched
+pub(crate) fn constant_time_eq(first: &[u8], second: &[u8]) -> bool {
+    first.len() == second.len()
+        && first
+            .iter()
+            .zip(second)
+            .fold(0, |difference, (first, second)| {
+                difference | (first ^ second)
+            })
+            == 0
+}
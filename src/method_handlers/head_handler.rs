@@ -1,11 +1,11 @@
 use std::convert::Infallible;
 use std::sync::Arc;
 
-use http_body_util::Full;
-use hyper::body::Bytes;
 use hyper::{Request, Response};
 
 use crate::cache::Cache;
+use crate::config::{Config, Site};
+use crate::method_handlers::handler_utils::response_body::{self, ResponseBody};
 use crate::method_handlers::{handler_utils, response_gen};
 use crate::resource_getters;
 
@@ -13,11 +13,22 @@ use crate::resource_getters;
 pub(crate) async fn handle_head(
     req: Request<hyper::body::Incoming>,
     cache: Arc<Cache>,
-) -> Result<Response<Full<Bytes>>, Infallible> {
-    match resource_getters::web_content::get_web_content(&req, Arc::clone(&cache)).await {
+    site: &Site<'_>,
+    config: &Config,
+) -> Result<Response<ResponseBody>, Infallible> {
+    match resource_getters::web_content::get_web_content(
+        &req,
+        Arc::clone(&cache),
+        site,
+        &config.compression,
+    )
+    .await
+    {
         Some(web_content) => {
-            let mut response = response_gen::get_resp::generate_response(&req, web_content).await?;
-            *response.body_mut() = Full::from(Bytes::new());
+            let mut response =
+                response_gen::get_resp::generate_response(&req, web_content, &config.handlers)
+                    .await?;
+            *response.body_mut() = response_body::empty();
             Ok(response)
         }
         None => handler_utils::packet_templates::send_error_packet(),
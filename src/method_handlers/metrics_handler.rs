@@ -0,0 +1,49 @@
+use std::convert::Infallible;
+
+use hyper::body::Bytes;
+use hyper::header::{ALLOW, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{Method, Request, Response, StatusCode};
+
+use crate::config::AdminConfig;
+use crate::method_handlers::handler_utils;
+use crate::method_handlers::handler_utils::response_body::{self, ResponseBody};
+use crate::metrics::Metrics;
+
+/// content type of the prometheus text exposition format
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Handles requests on an admin listener, which only serves the metrics page
+pub(crate) async fn handle_admin<B>(
+    req: &Request<B>,
+    metrics: &Metrics,
+    admin_config: &AdminConfig,
+) -> Result<Response<ResponseBody>, Infallible> {
+    if req.uri().path() != admin_config.metrics_path {
+        return handler_utils::packet_templates::send_not_found_packet(Bytes::new());
+    }
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        let response = Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .header(ALLOW, "GET, HEAD")
+            .body(response_body::empty())
+            .unwrap();
+        return Ok(response);
+    }
+
+    let rendered = Bytes::from(metrics.render().await);
+    let content_length = rendered.len();
+    let body = if req.method() == Method::HEAD {
+        response_body::empty()
+    } else {
+        response_body::full(rendered)
+    };
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, METRICS_CONTENT_TYPE)
+        .header(CONTENT_LENGTH, content_length)
+        .header(CACHE_CONTROL, "no-store")
+        .body(body)
+        .unwrap();
+    Ok(response)
+}
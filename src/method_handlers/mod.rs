@@ -3,8 +3,10 @@ pub mod delete_handler;
 pub mod get_handler;
 pub mod handler_utils;
 pub mod head_handler;
+pub mod metrics_handler;
 pub mod options_handler;
 pub mod post_handler;
 pub mod put_handler;
+pub mod redirect_handler;
 mod response_gen;
 pub mod trace_handler;
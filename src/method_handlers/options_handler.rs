@@ -1,22 +1,42 @@
 use std::convert::Infallible;
 
-use http_body_util::Full;
-use hyper::body::Bytes;
 use hyper::{Request, Response, StatusCode};
 
+use crate::config::Config;
+use crate::method_handlers::handler_utils::response_body::{self, ResponseBody};
+
 // Handles option requests, returning either a option response packet or server error packet
 pub(crate) async fn handle_option(
     _req: Request<hyper::body::Incoming>,
-) -> Result<Response<Full<Bytes>>, Infallible> {
-    //TODO: When completing handlers, add to the headers
+    config: &Config,
+) -> Result<Response<ResponseBody>, Infallible> {
+    let allowed_methods = allowed_methods(config);
     let response = Response::builder()
         .status(StatusCode::NO_CONTENT)
-        .header("Allow", "GET, OPTIONS")
-        .header("Access-Control-Allow-Methods", "GET, OPTIONS, HEAD")
+        .header("Allow", &allowed_methods)
+        .header("Access-Control-Allow-Methods", &allowed_methods)
         .header("Access-Control-Allow-Headers", "Content-Type")
         .header("Access-Control-Allow-Origin", "*")
         .header("Access-Control-Allow-Credentials", "true")
-        .body(Full::new(Bytes::new()))
+        .body(response_body::empty())
         .unwrap();
     Ok(response)
 }
+
+/// methods the server answers with more than 501, the optional ones depending on config
+fn allowed_methods(config: &Config) -> String {
+    let mut methods = vec!["GET", "HEAD", "OPTIONS"];
+    if config.write.enabled {
+        if config.write.upload.endpoint.is_some() {
+            methods.push("POST");
+        }
+        methods.extend(["PUT", "DELETE"]);
+    }
+    if config.trace.enabled {
+        methods.push("TRACE");
+    }
+    if config.proxy.enabled {
+        methods.push("CONNECT");
+    }
+    methods.join(", ")
+}
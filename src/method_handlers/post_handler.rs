@@ -1,16 +1,477 @@
 use std::convert::Infallible;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use http_body_util::Full;
-use hyper::body::Bytes;
+use http_body_util::BodyExt;
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{CONTENT_LENGTH, CONTENT_TYPE};
 use hyper::{Request, Response, StatusCode};
+use multer::{Field, Multipart};
+use serde_json::json;
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, error};
+use uuid::Uuid;
 
-// Handles option requests, returning either a option response packet or server error packet
+use crate::cache::Cache;
+use crate::config::{Config, UploadConfig};
+use crate::method_handlers::handler_utils;
+use crate::method_handlers::handler_utils::response_body::{self, ResponseBody};
+use crate::method_handlers::handler_utils::write_access;
+use crate::resource_getters::path_sanitiser;
+
+/// longest text field value kept for the summary
+const MAX_FIELD_BYTES: usize = 64 * 1024;
+
+/// largest urlencoded body read, as every field is held in memory
+const MAX_URLENCODED_BYTES: u64 = 1024 * 1024;
+
+/// prefix of the temp files uploads are streamed to, which don't count towards the quota
+const TEMP_FILE_PREFIX: &str = ".upload.";
+
+/// longest stored file name, leaving room for a counter within common filesystem limits
+const MAX_FILE_NAME_LENGTH: usize = 200;
+
+/// why a form couldn't be accepted
+enum FormError {
+    Malformed,
+    TooLarge,
+    QuotaExceeded,
+    Io(io::Error),
+}
+
+/// file written to the upload directory
+struct StoredFile {
+    field: String,
+    file_name: String,
+    stored_as: String,
+    path: PathBuf,
+    size: u64,
+    content_type: Option<String>,
+}
+
+/// what a form contained, returned to the client as json
+#[derive(Default)]
+struct FormSummary {
+    files: Vec<StoredFile>,
+    fields: Vec<(String, String)>,
+}
+
+/// bytes a form may still use, checked as each chunk arrives
+struct FormLimits {
+    request_remaining: u64,
+    // None when the upload directory has no quota
+    quota_remaining: Option<u64>,
+    max_file_bytes: u64,
+}
+
+// Handles post requests to the upload endpoint, storing the files of multipart forms and summarising the form as json
 pub(crate) async fn handle_post(
-    _req: Request<hyper::body::Incoming>,
-) -> Result<Response<Full<Bytes>>, Infallible> {
+    req: Request<Incoming>,
+    cache: Arc<Cache>,
+    config: &Config,
+) -> Result<Response<ResponseBody>, Infallible> {
+    let upload_config = &config.write.upload;
+    let is_endpoint = upload_config.endpoint.as_ref().is_some_and(|endpoint| {
+        path_sanitiser::normalise_path(req.uri().path())
+            .is_ok_and(|request_path| request_path == *endpoint)
+    });
+    if !config.write.enabled || !is_endpoint {
+        return handler_utils::packet_templates::send_not_implemented_packet();
+    }
+    if !write_access::is_authorized(&req, &config.write) {
+        return handler_utils::packet_templates::send_unauthorized_packet();
+    }
+
+    let declared_length = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|content_length| content_length.to_str().ok())
+        .and_then(|content_length| content_length.parse::<u64>().ok());
+    if declared_length
+        .is_some_and(|declared_length| declared_length > upload_config.max_request_bytes)
+    {
+        return handler_utils::packet_templates::send_payload_too_large_packet();
+    }
+
+    let content_type = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .unwrap_or("")
+        .to_string();
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+
+    let form_result = match essence.as_str() {
+        "multipart/form-data" => match multer::parse_boundary(&content_type) {
+            Ok(boundary) => read_multipart(req.into_body(), boundary, config).await,
+            Err(_) => Err(FormError::Malformed),
+        },
+        "application/x-www-form-urlencoded" => read_urlencoded(req.into_body()).await,
+        _ => return handler_utils::packet_templates::send_unsupported_media_type_packet(),
+    };
+
+    let summary = match form_result {
+        Ok(summary) => summary,
+        Err(FormError::Malformed) => {
+            return handler_utils::packet_templates::send_bad_request_packet()
+        }
+        Err(FormError::TooLarge) => {
+            return handler_utils::packet_templates::send_payload_too_large_packet()
+        }
+        Err(FormError::QuotaExceeded) => {
+            return handler_utils::packet_templates::send_insufficient_storage_packet()
+        }
+        Err(FormError::Io(err)) => {
+            error!(%err, "Could not store upload");
+            return handler_utils::packet_templates::send_error_packet();
+        }
+    };
+
+    // the upload directory may be served, so listings of it go stale
+    let stored_paths: Vec<PathBuf> = summary.files.iter().map(|file| file.path.clone()).collect();
+    if !stored_paths.is_empty() {
+        Cache::invalidate_paths(Arc::clone(&cache), &stored_paths).await;
+    }
+
+    let body = Bytes::from(summary.to_json());
     let response = Response::builder()
-        .status(StatusCode::NOT_IMPLEMENTED)
-        .body(Full::new(Bytes::new()))
+        .status(if summary.files.is_empty() {
+            StatusCode::OK
+        } else {
+            StatusCode::CREATED
+        })
+        .header(CONTENT_TYPE, "application/json")
+        .header(CONTENT_LENGTH, body.len())
+        .body(response_body::full(body))
         .unwrap();
     Ok(response)
 }
+
+/// stores every file part and collects the text fields. Nothing is kept if any part fails.
+async fn read_multipart(
+    body: Incoming,
+    boundary: String,
+    config: &Config,
+) -> Result<FormSummary, FormError> {
+    let upload_config = &config.write.upload;
+    let quota_remaining = match upload_config.quota_bytes {
+        Some(quota_bytes) => {
+            Some(quota_bytes.saturating_sub(write_access::WRITE_LOCK.lock().await.upload_usage))
+        }
+        None => None,
+    };
+    let mut limits = FormLimits {
+        request_remaining: upload_config.max_request_bytes,
+        quota_remaining,
+        max_file_bytes: config.write.max_bytes,
+    };
+
+    let mut multipart = Multipart::new(body.into_data_stream(), boundary);
+    let mut summary = FormSummary::default();
+
+    let result = async {
+        while let Some(mut field) = multipart
+            .next_field()
+            .await
+            .map_err(|_| FormError::Malformed)?
+        {
+            if field.file_name().is_none() {
+                let name = field.name().unwrap_or_default().to_string();
+                let value = read_text_field(&mut field, &mut limits).await?;
+                summary.fields.push((name, value));
+                continue;
+            }
+
+            if summary.files.len() >= upload_config.max_files {
+                return Err(FormError::TooLarge);
+            }
+            let stored_file = store_file(field, upload_config, &mut limits).await?;
+            debug!(
+                stored_as = %stored_file.stored_as,
+                size = stored_file.size,
+                "Stored upload"
+            );
+            summary.files.push(stored_file);
+        }
+        Ok(())
+    }
+    .await;
+
+    if let Err(form_error) = result {
+        let mut write_state = write_access::WRITE_LOCK.lock().await;
+        for stored_file in &summary.files {
+            if fs::remove_file(&stored_file.path).await.is_ok() {
+                write_state.resize_upload(upload_config, &stored_file.path, stored_file.size, 0);
+            }
+        }
+        return Err(form_error);
+    }
+    Ok(summary)
+}
+
+/// reads a text field, which counts towards the request limit
+async fn read_text_field(
+    field: &mut Field<'static>,
+    limits: &mut FormLimits,
+) -> Result<String, FormError> {
+    let mut value = Vec::new();
+    while let Some(chunk) = field.chunk().await.map_err(|_| FormError::Malformed)? {
+        limits.take_request_bytes(chunk.len() as u64)?;
+        if value.len() + chunk.len() > MAX_FIELD_BYTES {
+            return Err(FormError::TooLarge);
+        }
+        value.extend_from_slice(&chunk);
+    }
+    Ok(String::from_utf8_lossy(&value).into_owned())
+}
+
+/// streams a file part to a temp file, then moves it to a free name derived from the client's file name
+async fn store_file(
+    mut field: Field<'static>,
+    upload_config: &UploadConfig,
+    limits: &mut FormLimits,
+) -> Result<StoredFile, FormError> {
+    let field_name = field.name().unwrap_or_default().to_string();
+    let file_name = field.file_name().unwrap_or_default().to_string();
+    let content_type = field.content_type().map(|mime| mime.to_string());
+
+    let temp_path = upload_config.directory.join(format!(
+        "{}{}.tmp",
+        TEMP_FILE_PREFIX,
+        Uuid::new_v4().simple()
+    ));
+    let written = async {
+        let mut file = File::options()
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)
+            .await
+            .map_err(FormError::Io)?;
+
+        let mut written: u64 = 0;
+        while let Some(chunk) = field.chunk().await.map_err(|_| FormError::Malformed)? {
+            written += chunk.len() as u64;
+            if written > limits.max_file_bytes {
+                return Err(FormError::TooLarge);
+            }
+            limits.take_request_bytes(chunk.len() as u64)?;
+            limits.take_quota_bytes(chunk.len() as u64)?;
+            file.write_all(&chunk).await.map_err(FormError::Io)?;
+        }
+        file.sync_all().await.map_err(FormError::Io)?;
+        Ok(written)
+    }
+    .await;
+
+    let stored = match written {
+        Ok(written) => move_to_free_name(&temp_path, upload_config, &file_name, written)
+            .await
+            .map(|(path, stored_as)| (written, path, stored_as)),
+        Err(form_error) => Err(form_error),
+    };
+    let (size, path, stored_as) = match stored {
+        Ok(stored) => stored,
+        Err(form_error) => {
+            let _ = fs::remove_file(&temp_path).await;
+            return Err(form_error);
+        }
+    };
+
+    Ok(StoredFile {
+        field: field_name,
+        file_name,
+        stored_as,
+        path,
+        size,
+        content_type,
+    })
+}
+
+/// renames the temp file to the sanitised name, adding a counter rather than replacing an existing file.
+/// The quota is checked again here, as uploads running at the same time each started with the same usage.
+async fn move_to_free_name(
+    temp_path: &Path,
+    upload_config: &UploadConfig,
+    file_name: &str,
+    size: u64,
+) -> Result<(PathBuf, String), FormError> {
+    let directory = &upload_config.directory;
+    let sanitised = sanitise_file_name(file_name);
+    let (stem, extension) = match sanitised.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (sanitised.as_str(), String::new()),
+    };
+
+    // the quota and names are checked and taken without another write in between
+    let mut write_state = write_access::WRITE_LOCK.lock().await;
+    if let Some(quota_bytes) = upload_config.quota_bytes {
+        if write_state.upload_usage.saturating_add(size) > quota_bytes {
+            return Err(FormError::QuotaExceeded);
+        }
+    }
+
+    let mut counter = 0;
+    loop {
+        let candidate = match counter {
+            0 => sanitised.clone(),
+            _ => format!("{}-{}{}", stem, counter, extension),
+        };
+        let path = directory.join(&candidate);
+        if !fs::try_exists(&path).await.map_err(FormError::Io)? {
+            fs::rename(temp_path, &path).await.map_err(FormError::Io)?;
+            write_state.resize_upload(upload_config, &path, 0, size);
+            return Ok((path, candidate));
+        }
+        counter += 1;
+    }
+}
+
+/// collects the fields of a urlencoded body
+async fn read_urlencoded(body: Incoming) -> Result<FormSummary, FormError> {
+    let mut body = body;
+    let mut data = Vec::new();
+    while let Some(frame) = body.frame().await {
+        let frame = frame.map_err(|_| FormError::Malformed)?;
+        if let Ok(chunk) = frame.into_data() {
+            if (data.len() + chunk.len()) as u64 > MAX_URLENCODED_BYTES {
+                return Err(FormError::TooLarge);
+            }
+            data.extend_from_slice(&chunk);
+        }
+    }
+
+    Ok(FormSummary {
+        files: Vec::new(),
+        fields: form_urlencoded::parse(&data).into_owned().collect(),
+    })
+}
+
+/// file name without any directories, limited to characters that are safe on every filesystem.
+/// Leading dots are removed so uploads can't be hidden or named . or ..
+fn sanitise_file_name(file_name: &str) -> String {
+    let base_name = file_name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let sanitised: String = base_name
+        .chars()
+        .map(|character| {
+            if character.is_ascii_alphanumeric() || matches!(character, '.' | '-' | '_') {
+                character
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    // only ascii is left, so truncating can't split a character
+    let mut sanitised = sanitised.trim_start_matches('.').to_string();
+    sanitised.truncate(MAX_FILE_NAME_LENGTH);
+    if sanitised.is_empty() {
+        sanitised = "upload".to_string();
+    }
+    sanitised
+}
+
+/// counts the bytes already in the upload directory, which the quota is checked against from then on.
+/// Called once at startup, files changed there by anything but this server aren't noticed.
+pub(crate) async fn seed_upload_usage(upload_config: &UploadConfig) -> io::Result<()> {
+    let usage = directory_usage(&upload_config.directory).await?;
+    write_access::WRITE_LOCK.lock().await.upload_usage = usage;
+    Ok(())
+}
+
+/// bytes used by the files directly inside the upload directory, leaving out uploads in progress
+async fn directory_usage(directory: &Path) -> io::Result<u64> {
+    let mut usage = 0;
+    let mut read_dir = fs::read_dir(directory).await?;
+    while let Some(dir_entry) = read_dir.next_entry().await? {
+        if dir_entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with(TEMP_FILE_PREFIX)
+        {
+            continue;
+        }
+        let metadata = dir_entry.metadata().await?;
+        if metadata.is_file() {
+            usage += metadata.len();
+        }
+    }
+    Ok(usage)
+}
+
+impl FormLimits {
+    fn take_request_bytes(&mut self, length: u64) -> Result<(), FormError> {
+        self.request_remaining = self
+            .request_remaining
+            .checked_sub(length)
+            .ok_or(FormError::TooLarge)?;
+        Ok(())
+    }
+
+    fn take_quota_bytes(&mut self, length: u64) -> Result<(), FormError> {
+        if let Some(quota_remaining) = &mut self.quota_remaining {
+            *quota_remaining = quota_remaining
+                .checked_sub(length)
+                .ok_or(FormError::QuotaExceeded)?;
+        }
+        Ok(())
+    }
+}
+
+impl FormSummary {
+    fn to_json(&self) -> String {
+        let files: Vec<_> = self
+            .files
+            .iter()
+            .map(|file| {
+                json!({
+                    "field": file.field,
+                    "filename": file.file_name,
+                    "stored_as": file.stored_as,
+                    "size": file.size,
+                    "content_type": file.content_type,
+                })
+            })
+            .collect();
+        let fields: Vec<_> = self
+            .fields
+            .iter()
+            .map(|(name, value)| json!({ "name": name, "value": value }))
+            .collect();
+
+        json!({ "files": files, "fields": fields }).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitises_file_names() {
+        for (file_name, expected) in [
+            ("report.pdf", "report.pdf"),
+            ("../../etc/passwd", "passwd"),
+            ("C:\\Users\\me\\photo 1.jpg", "photo_1.jpg"),
+            ("..", "upload"),
+            (".hidden", "hidden"),
+            ("...", "upload"),
+            ("", "upload"),
+            ("dir/", "upload"),
+            ("naïve<script>.html", "na_ve_script_.html"),
+            ("a\0b", "a_b"),
+        ] {
+            assert_eq!(sanitise_file_name(file_name), expected, "{:?}", file_name);
+        }
+        assert_eq!(
+            sanitise_file_name(&"x".repeat(300)).len(),
+            MAX_FILE_NAME_LENGTH
+        );
+    }
+}
@@ -1,16 +1,157 @@
 use std::convert::Infallible;
+use std::path::Path;
+use std::sync::Arc;
 
-use http_body_util::Full;
-use hyper::body::Bytes;
-use hyper::{Request, Response, StatusCode};
+use http_body_util::BodyExt;
+use hyper::header::CONTENT_LENGTH;
+use hyper::{Request, Response};
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+use tracing::error;
 
-// Handles option requests, returning either a option response packet or server error packet
+use crate::cache::Cache;
+use crate::config::{Config, Site};
+use crate::method_handlers::handler_utils;
+use crate::method_handlers::handler_utils::response_body::ResponseBody;
+use crate::method_handlers::handler_utils::write_access;
+use crate::resource_getters::dir_accessor;
+use crate::resource_getters::path_sanitiser::{self, PathError};
+
+/// why an upload couldn't be written to its temp file
+enum UploadError {
+    TooLarge,
+    Body,
+    Io(std::io::Error),
+}
+
+// Handles put requests, writing the body to the document root and returning 201 if the file is new or 204 if it was replaced
 pub(crate) async fn handle_put(
-    _req: Request<hyper::body::Incoming>,
-) -> Result<Response<Full<Bytes>>, Infallible> {
-    let response = Response::builder()
-        .status(StatusCode::NOT_IMPLEMENTED)
-        .body(Full::new(Bytes::new()))
-        .unwrap();
-    Ok(response)
+    req: Request<hyper::body::Incoming>,
+    cache: Arc<Cache>,
+    site: &Site<'_>,
+    config: &Config,
+) -> Result<Response<ResponseBody>, Infallible> {
+    if !config.write.enabled {
+        return handler_utils::packet_templates::send_not_implemented_packet();
+    }
+    if !write_access::is_authorized(&req, &config.write) {
+        return handler_utils::packet_templates::send_unauthorized_packet();
+    }
+
+    let (path, request_path) = match write_access::resolve_target(&req, site) {
+        Ok(Some(target)) => target,
+        Ok(None) => return handler_utils::packet_templates::send_conflict_packet(),
+        Err(PathError::Malformed) => {
+            return handler_utils::packet_templates::send_bad_request_packet()
+        }
+        Err(PathError::Forbidden) => {
+            return handler_utils::packet_templates::send_forbidden_packet()
+        }
+    };
+    // a directory can't be replaced by a file
+    if request_path.ends_with('/') || path.is_dir() {
+        return handler_utils::packet_templates::send_conflict_packet();
+    }
+
+    // refuse what is known to be too large or to fail its preconditions before reading the body
+    let declared_length = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|content_length| content_length.to_str().ok())
+        .and_then(|content_length| content_length.parse::<u64>().ok());
+    if declared_length.is_some_and(|declared_length| declared_length > config.write.max_bytes) {
+        return handler_utils::packet_templates::send_payload_too_large_packet();
+    }
+    let current_version = write_access::current_version(&path, site.resources).await;
+    if !write_access::preconditions_hold(req.headers(), current_version.as_ref()) {
+        return handler_utils::packet_templates::send_precondition_failed_packet();
+    }
+
+    // the body goes to a temp file first so readers never see a partial upload
+    let (parts, body) = req.into_parts();
+    let temp_path = dir_accessor::staging_path(&path);
+    let written = match write_upload(body, &temp_path, &path, config.write.max_bytes).await {
+        Ok(written) => written,
+        Err(upload_error) => {
+            let _ = fs::remove_file(&temp_path).await;
+            return match upload_error {
+                UploadError::TooLarge => {
+                    handler_utils::packet_templates::send_payload_too_large_packet()
+                }
+                UploadError::Body => handler_utils::packet_templates::send_bad_request_packet(),
+                UploadError::Io(err) => {
+                    error!(%err, path = %path.display(), "Could not write upload");
+                    handler_utils::packet_templates::send_error_packet()
+                }
+            };
+        }
+    };
+
+    // check again now the body has arrived, as another write may have replaced the file meanwhile
+    let mut write_state = write_access::WRITE_LOCK.lock().await;
+    let current_version = write_access::current_version(&path, site.resources).await;
+    if !write_access::preconditions_hold(&parts.headers, current_version.as_ref()) {
+        drop(write_state);
+        let _ = fs::remove_file(&temp_path).await;
+        return handler_utils::packet_templates::send_precondition_failed_packet();
+    }
+    let replaced = fs::symlink_metadata(&path)
+        .await
+        .ok()
+        .filter(|metadata| metadata.is_file())
+        .map_or(0, |metadata| metadata.len());
+    if let Err(err) = fs::rename(&temp_path, &path).await {
+        drop(write_state);
+        error!(%err, path = %path.display(), "Could not move upload into place");
+        let _ = fs::remove_file(&temp_path).await;
+        return handler_utils::packet_templates::send_error_packet();
+    }
+    write_state.resize_upload(&config.write.upload, &path, replaced, written);
+    drop(write_state);
+
+    Cache::invalidate_paths(Arc::clone(&cache), &[path]).await;
+
+    match current_version {
+        Some(_) => handler_utils::packet_templates::send_no_content_packet(),
+        None => handler_utils::packet_templates::send_created_packet(&path_sanitiser::encode_path(
+            &request_path,
+        )),
+    }
+}
+
+/// streams the body into the temp file, giving it the permissions of the file it will replace.
+/// Returns the number of bytes written.
+async fn write_upload(
+    mut body: hyper::body::Incoming,
+    temp_path: &Path,
+    path: &Path,
+    max_bytes: u64,
+) -> Result<u64, UploadError> {
+    let mut file = File::options()
+        .write(true)
+        .create_new(true)
+        .open(temp_path)
+        .await
+        .map_err(UploadError::Io)?;
+
+    let mut written: u64 = 0;
+    while let Some(frame) = body.frame().await {
+        let frame = frame.map_err(|_| UploadError::Body)?;
+        if let Ok(data) = frame.into_data() {
+            written += data.len() as u64;
+            if written > max_bytes {
+                return Err(UploadError::TooLarge);
+            }
+            file.write_all(&data).await.map_err(UploadError::Io)?;
+        }
+    }
+
+    if let Ok(metadata) = fs::metadata(path).await {
+        fs::set_permissions(temp_path, metadata.permissions())
+            .await
+            .map_err(UploadError::Io)?;
+    }
+    // flushed to disk before the rename makes it visible
+    file.sync_all().await.map_err(UploadError::Io)?;
+    Ok(written)
 }
@@ -0,0 +1,53 @@
+use std::convert::Infallible;
+
+use hyper::{Request, Response, StatusCode};
+
+use crate::config::RedirectConfig;
+use crate::method_handlers::handler_utils;
+use crate::method_handlers::handler_utils::response_body::ResponseBody;
+use crate::resource_getters::path_sanitiser;
+use crate::resource_getters::resource_key;
+
+/// true if the request path is on the whitelist served directly over plaintext
+pub(crate) fn is_passthrough<B>(req: &Request<B>, redirect_config: &RedirectConfig) -> bool {
+    // compare against the normalised path so dot segments can't smuggle other paths through
+    match path_sanitiser::normalise_path(req.uri().path()) {
+        Ok(request_path) => redirect_config
+            .passthrough
+            .iter()
+            .any(|prefix| request_path.starts_with(prefix.as_str())),
+        Err(_) => false,
+    }
+}
+
+/// Handles requests on a redirect listener, sending the client to the same path and query over https
+pub(crate) fn handle_redirect<B>(
+    req: &Request<B>,
+    redirect_config: &RedirectConfig,
+) -> Result<Response<ResponseBody>, Infallible> {
+    let host = match &redirect_config.host {
+        Some(host) => host.clone(),
+        None => resource_key::normalise_host(req),
+    };
+    if host.is_empty() {
+        return handler_utils::packet_templates::send_bad_request_packet();
+    }
+
+    let port = match redirect_config.https_port {
+        443 => String::new(),
+        https_port => format!(":{}", https_port),
+    };
+    let path_and_query = req
+        .uri()
+        .path_and_query()
+        .map_or("/", |path_and_query| path_and_query.as_str());
+
+    // config validation only allows 301 and 308
+    let status =
+        StatusCode::from_u16(redirect_config.status).unwrap_or(StatusCode::MOVED_PERMANENTLY);
+
+    handler_utils::packet_templates::send_redirect_packet(
+        status,
+        &format!("https://{}{}{}", host, port, path_and_query),
+    )
+}
@@ -1,23 +1,92 @@
 use std::convert::Infallible;
 
-use http_body_util::Full;
-use hyper::body::Bytes;
-use hyper::{Request, Response};
+use hyper::header::{HeaderValue, CONTENT_ENCODING, VARY};
+use hyper::{Method, Request, Response, StatusCode};
+use tracing::instrument;
 
+use crate::config::HandlerConfig;
 use crate::method_handlers::handler_utils;
+use crate::method_handlers::handler_utils::response_body::ResponseBody;
+use crate::resource_getters::path_sanitiser::PathError;
 use crate::resource_getters::web_content::WebContent;
+use crate::sendfile::SendfileQueue;
 
+#[instrument(name = "response_generation", level = "debug", skip_all)]
 pub(crate) async fn generate_response(
     req: &Request<hyper::body::Incoming>,
     web_content: WebContent,
-) -> Result<Response<Full<Bytes>>, Infallible> {
+    handler_config: &HandlerConfig,
+) -> Result<Response<ResponseBody>, Infallible> {
+    let vary = web_content.get_vary().join(", ");
+    let content_encoding = web_content.get_content_encoding();
+    let cache_status = web_content.get_cache_status();
+
+    // only plaintext http/1.1 connections carry a queue, and encoded bodies are left to hyper
+    let sendfile = match content_encoding {
+        None if req.method() == Method::GET => req.extensions().get::<SendfileQueue>(),
+        _ => None,
+    };
+    let mut response = respond(req, web_content, handler_config, sendfile)?;
+
+    // tell caches which request headers the representation depends on, including on 304s
+    if !vary.is_empty() {
+        if let Ok(vary) = HeaderValue::from_str(&vary) {
+            response.headers_mut().insert(VARY, vary);
+        }
+    }
+
+    // ranges and bodies are of the encoded representation
+    if let Some(encoding) = content_encoding {
+        if matches!(
+            response.status(),
+            StatusCode::OK | StatusCode::PARTIAL_CONTENT
+        ) {
+            response
+                .headers_mut()
+                .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.token()));
+        }
+    }
+    if let Some(cache_status) = cache_status {
+        response.extensions_mut().insert(cache_status);
+    }
+    Ok(response)
+}
+
+/// picks the status and body from the request's preconditions and ranges
+fn respond(
+    req: &Request<hyper::body::Incoming>,
+    web_content: WebContent,
+    handler_config: &HandlerConfig,
+    sendfile: Option<&SendfileQueue>,
+) -> Result<Response<ResponseBody>, Infallible> {
+    // Check if the request path was refused
+    match web_content.get_rejection() {
+        Some(PathError::Malformed) => {
+            return handler_utils::packet_templates::send_bad_request_packet()
+        }
+        Some(PathError::Forbidden) => {
+            return handler_utils::packet_templates::send_forbidden_packet()
+        }
+        None => {}
+    }
+
+    // Send directories requested without a trailing slash to the canonical url
+    if let Some(location) = web_content.get_redirect() {
+        return handler_utils::packet_templates::send_redirect_packet(
+            StatusCode::MOVED_PERMANENTLY,
+            location,
+        );
+    }
+
     // Check if the content is a 404 Not Found
     if web_content.is_not_found() {
         return handler_utils::packet_templates::send_not_found_packet(
-            web_content.get_data().clone(),
+            web_content.get_not_found_page().unwrap().clone(),
         );
     }
 
+    let body = web_content.get_body().unwrap();
+
     // If it's not a 404, proceed with the regular content
     let mut valid_is_match = false;
     let mut valid_if_none_match = false;
@@ -74,27 +143,31 @@ pub(crate) async fn generate_response(
             web_content.get_etag().unwrap(),
             date_header,
         ) {
-            if let Some(sliced_content) =
-                handler_utils::header_evals::range(web_content.get_data(), range_header)
-            {
-                return if sliced_content.len() == 1 {
-                    let (data, start, end) = &sliced_content[0];
+            if let Some(ranges) = handler_utils::header_evals::range(body.length(), range_header) {
+                return if ranges.len() == 1 {
+                    let (start, end) = &ranges[0];
                     handler_utils::packet_templates::send_partial_content_packet(
-                        data.clone(),
+                        body.range_chunks(*start, *end, sendfile),
                         start,
                         end,
-                        &web_content.get_data().len(),
+                        &body.length(),
                         web_content.get_content_type().unwrap(),
                         web_content.get_last_modified().unwrap(),
                         web_content.get_etag().unwrap(),
+                        handler_config,
                     )
                 } else {
+                    let sliced_content = ranges
+                        .into_iter()
+                        .map(|(start, end)| (body.range_chunks(start, end, None), start, end))
+                        .collect();
                     handler_utils::packet_templates::send_multipart_packet(
                         sliced_content,
-                        &web_content.get_data().len(),
+                        &body.length(),
                         web_content.get_content_type().unwrap(),
                         web_content.get_last_modified().unwrap(),
                         web_content.get_etag().unwrap(),
+                        handler_config,
                     )
                 };
             }
@@ -103,9 +176,11 @@ pub(crate) async fn generate_response(
 
     // If no If-Range header/is a HEAD request, send ok response
     handler_utils::packet_templates::send_default_ok_packet(
-        web_content.get_data().clone(),
+        body.to_response_body(sendfile),
+        body.length(),
         web_content.get_content_type().unwrap(),
         web_content.get_last_modified().unwrap().to_owned(),
         web_content.get_etag().unwrap(),
+        handler_config,
     )
 }
@@ -1,16 +1,65 @@
 use std::convert::Infallible;
 
-use http_body_util::Full;
 use hyper::body::Bytes;
+use hyper::header::{
+    AUTHORIZATION, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE, COOKIE, PROXY_AUTHORIZATION,
+    TRANSFER_ENCODING,
+};
 use hyper::{Request, Response, StatusCode};
 
-// Handles option requests, returning either a option response packet or server error packet
+use crate::config::TraceConfig;
+use crate::method_handlers::handler_utils;
+use crate::method_handlers::handler_utils::response_body::{self, ResponseBody};
+
+/// value echoed in place of a sensitive header
+const REDACTED: &str = "[redacted]";
+
+// Handles trace requests, echoing the received request back as message/http with credentials redacted
 pub(crate) async fn handle_trace(
-    _req: Request<hyper::body::Incoming>,
-) -> Result<Response<Full<Bytes>>, Infallible> {
+    req: Request<hyper::body::Incoming>,
+    trace_config: &TraceConfig,
+) -> Result<Response<ResponseBody>, Infallible> {
+    if !trace_config.enabled {
+        return handler_utils::packet_templates::send_not_implemented_packet();
+    }
+
+    // a TRACE request must not have content
+    let has_content = req.headers().contains_key(TRANSFER_ENCODING)
+        || req
+            .headers()
+            .get(CONTENT_LENGTH)
+            .is_some_and(|content_length| content_length != "0");
+    if has_content {
+        return handler_utils::packet_templates::send_bad_request_packet();
+    }
+
+    let mut message = format!("{} {} {:?}\r\n", req.method(), req.uri(), req.version());
+    for (name, value) in req.headers() {
+        let is_sensitive = [AUTHORIZATION, PROXY_AUTHORIZATION, COOKIE].contains(name)
+            || trace_config
+                .redact_headers
+                .iter()
+                .any(|redacted| redacted == name.as_str());
+        let value = if is_sensitive {
+            REDACTED.to_string()
+        } else {
+            String::from_utf8_lossy(value.as_bytes()).into_owned()
+        };
+        message.push_str(&format!("{}: {}\r\n", name, value));
+
+        if message.len() > trace_config.max_bytes {
+            return handler_utils::packet_templates::send_payload_too_large_packet();
+        }
+    }
+    message.push_str("\r\n");
+
+    let body = Bytes::from(message);
     let response = Response::builder()
-        .status(StatusCode::NOT_IMPLEMENTED)
-        .body(Full::new(Bytes::new()))
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "message/http")
+        .header(CONTENT_LENGTH, body.len())
+        .header(CACHE_CONTROL, "no-store")
+        .body(response_body::full(body))
         .unwrap();
     Ok(response)
 }
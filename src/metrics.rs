@@ -0,0 +1,272 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use hyper::{Method, Response, StatusCode};
+
+use crate::cache::Cache;
+use crate::method_handlers::handler_utils::response_body::{self, ResponseBody};
+
+/// upper bounds of the request duration histogram buckets, in seconds
+const DURATION_BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// request, connection and cache counters, rendered in the prometheus text format
+pub(crate) struct Metrics {
+    // (method, status) -> requests, recorded once the response body is finished
+    requests: Mutex<BTreeMap<(&'static str, u16), u64>>,
+    durations: Mutex<BTreeMap<&'static str, Histogram>>,
+    bytes_sent: AtomicU64,
+    open_connections: AtomicU64,
+    // outcomes of the conditional and range request handling
+    not_modified: AtomicU64,
+    partial_content: AtomicU64,
+    precondition_failed: AtomicU64,
+    cache: Arc<Cache>,
+}
+
+/// request durations of one method, counted per bucket rather than cumulatively
+#[derive(Default)]
+struct Histogram {
+    bucket_counts: [u64; DURATION_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+/// counts a connection as open until it is dropped
+pub(crate) struct ConnectionGuard {
+    metrics: Arc<Metrics>,
+}
+
+impl Metrics {
+    pub(crate) fn new(cache: Arc<Cache>) -> Arc<Self> {
+        Arc::new(Self {
+            requests: Mutex::new(BTreeMap::new()),
+            durations: Mutex::new(BTreeMap::new()),
+            bytes_sent: AtomicU64::new(0),
+            open_connections: AtomicU64::new(0),
+            not_modified: AtomicU64::new(0),
+            partial_content: AtomicU64::new(0),
+            precondition_failed: AtomicU64::new(0),
+            cache,
+        })
+    }
+
+    /// wraps the response body so the request is recorded once the body has been sent
+    pub(crate) fn observe_response(
+        self: &Arc<Self>,
+        method: &Method,
+        started: Instant,
+        response: Response<ResponseBody>,
+    ) -> Response<ResponseBody> {
+        let method = method_label(method);
+        let status = response.status();
+        let metrics = Arc::clone(self);
+
+        response.map(|body| {
+            response_body::with_completion(body, move |bytes_sent| {
+                metrics.record(method, status, started, bytes_sent);
+            })
+        })
+    }
+
+    /// marks a connection as open for as long as the returned guard lives
+    pub(crate) fn connection_opened(self: &Arc<Self>) -> ConnectionGuard {
+        self.open_connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard {
+            metrics: Arc::clone(self),
+        }
+    }
+
+    fn record(&self, method: &'static str, status: StatusCode, started: Instant, bytes_sent: u64) {
+        let duration = started.elapsed().as_secs_f64();
+
+        *self
+            .requests
+            .lock()
+            .unwrap()
+            .entry((method, status.as_u16()))
+            .or_default() += 1;
+
+        let mut durations = self.durations.lock().unwrap();
+        let histogram = durations.entry(method).or_default();
+        if let Some(bucket) = DURATION_BUCKETS
+            .iter()
+            .position(|upper_bound| duration <= *upper_bound)
+        {
+            histogram.bucket_counts[bucket] += 1;
+        }
+        histogram.count += 1;
+        histogram.sum += duration;
+        drop(durations);
+
+        self.bytes_sent.fetch_add(bytes_sent, Ordering::Relaxed);
+
+        let conditional_counter = match status {
+            StatusCode::NOT_MODIFIED => Some(&self.not_modified),
+            StatusCode::PARTIAL_CONTENT => Some(&self.partial_content),
+            StatusCode::PRECONDITION_FAILED => Some(&self.precondition_failed),
+            _ => None,
+        };
+        if let Some(counter) = conditional_counter {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// renders every metric in the prometheus text exposition format
+    pub(crate) async fn render(&self) -> String {
+        let mut output = String::new();
+
+        write_header(
+            &mut output,
+            "web_server_http_requests_total",
+            "counter",
+            "Requests served, by method and status.",
+        );
+        for ((method, status), count) in self.requests.lock().unwrap().iter() {
+            let _ = writeln!(
+                output,
+                "web_server_http_requests_total{{method=\"{}\",status=\"{}\"}} {}",
+                method, status, count
+            );
+        }
+
+        write_header(
+            &mut output,
+            "web_server_http_request_duration_seconds",
+            "histogram",
+            "Time from receiving a request until its response body was sent, by method.",
+        );
+        for (method, histogram) in self.durations.lock().unwrap().iter() {
+            let mut cumulative_count = 0;
+            for (upper_bound, bucket_count) in DURATION_BUCKETS.iter().zip(histogram.bucket_counts)
+            {
+                cumulative_count += bucket_count;
+                let _ = writeln!(
+                    output,
+                    "web_server_http_request_duration_seconds_bucket{{method=\"{}\",le=\"{}\"}} {}",
+                    method, upper_bound, cumulative_count
+                );
+            }
+            let _ = writeln!(
+                output,
+                "web_server_http_request_duration_seconds_bucket{{method=\"{}\",le=\"+Inf\"}} {}\n\
+                 web_server_http_request_duration_seconds_sum{{method=\"{}\"}} {}\n\
+                 web_server_http_request_duration_seconds_count{{method=\"{}\"}} {}",
+                method, histogram.count, method, histogram.sum, method, histogram.count
+            );
+        }
+
+        write_single(
+            &mut output,
+            "web_server_http_response_bytes_total",
+            "counter",
+            "Response body bytes sent.",
+            self.bytes_sent.load(Ordering::Relaxed),
+        );
+        write_single(
+            &mut output,
+            "web_server_open_connections",
+            "gauge",
+            "Client connections currently open.",
+            self.open_connections.load(Ordering::Relaxed),
+        );
+
+        write_header(
+            &mut output,
+            "web_server_conditional_responses_total",
+            "counter",
+            "Responses decided by conditional and range request headers, by outcome.",
+        );
+        for (outcome, counter) in [
+            ("not_modified", &self.not_modified),
+            ("partial_content", &self.partial_content),
+            ("precondition_failed", &self.precondition_failed),
+        ] {
+            let _ = writeln!(
+                output,
+                "web_server_conditional_responses_total{{outcome=\"{}\"}} {}",
+                outcome,
+                counter.load(Ordering::Relaxed)
+            );
+        }
+
+        let cache_stats = Cache::stats(Arc::clone(&self.cache)).await;
+        for (name, metric_type, help, value) in [
+            (
+                "web_server_cache_entries",
+                "gauge",
+                "Entries held in the cache.",
+                cache_stats.entries as u64,
+            ),
+            (
+                "web_server_cache_bytes",
+                "gauge",
+                "Bytes held in the cache.",
+                cache_stats.bytes as u64,
+            ),
+            (
+                "web_server_cache_hits_total",
+                "counter",
+                "Cache lookups that found an entry.",
+                cache_stats.hits,
+            ),
+            (
+                "web_server_cache_misses_total",
+                "counter",
+                "Cache lookups that found nothing.",
+                cache_stats.misses,
+            ),
+            (
+                "web_server_cache_evictions_total",
+                "counter",
+                "Entries evicted to keep the cache within its byte budget.",
+                cache_stats.evictions,
+            ),
+        ] {
+            write_single(&mut output, name, metric_type, help, value);
+        }
+
+        output
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.metrics
+            .open_connections
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// label for a request method, grouping extension methods so clients can't create unbounded series
+fn method_label(method: &Method) -> &'static str {
+    match *method {
+        Method::GET => "GET",
+        Method::HEAD => "HEAD",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::DELETE => "DELETE",
+        Method::OPTIONS => "OPTIONS",
+        Method::TRACE => "TRACE",
+        Method::CONNECT => "CONNECT",
+        Method::PATCH => "PATCH",
+        _ => "OTHER",
+    }
+}
+
+fn write_header(output: &mut String, name: &str, metric_type: &str, help: &str) {
+    let _ = writeln!(
+        output,
+        "# HELP {} {}\n# TYPE {} {}",
+        name, help, name, metric_type
+    );
+}
+
+fn write_single(output: &mut String, name: &str, metric_type: &str, help: &str, value: u64) {
+    write_header(output, name, metric_type, help);
+    let _ = writeln!(output, "{} {}", name, value);
+}
@@ -0,0 +1,29 @@
+use hyper::header::HeaderName;
+use hyper::Request;
+use uuid::Uuid;
+
+/// header carrying the request id, echoed on the response
+pub(crate) const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// longest client supplied id that is kept
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// the client's X-Request-Id if it is safe to put in logs and headers, otherwise a new random id
+pub(crate) fn from_request<B>(req: &Request<B>) -> String {
+    let client_id = req
+        .headers()
+        .get(X_REQUEST_ID)
+        .and_then(|request_id| request_id.to_str().ok())
+        .filter(|request_id| {
+            !request_id.is_empty()
+                && request_id.len() <= MAX_REQUEST_ID_LENGTH
+                && request_id.chars().all(|character| {
+                    character.is_ascii_alphanumeric() || matches!(character, '-' | '_' | '.' | ':')
+                })
+        });
+
+    match client_id {
+        Some(request_id) => request_id.to_string(),
+        None => Uuid::new_v4().to_string(),
+    }
+}
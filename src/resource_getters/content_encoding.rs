@@ -0,0 +1,205 @@
+use std::io::{self, Write};
+
+use flate2::write::GzEncoder;
+use hyper::body::Bytes;
+use hyper::header::{HeaderMap, ACCEPT_ENCODING};
+
+/// quality levels used when compressing, moderate as each variant is compressed once and then cached
+const GZIP_LEVEL: u32 = 6;
+const BROTLI_QUALITY: u32 = 6;
+const BROTLI_WINDOW: u32 = 22;
+const ZSTD_LEVEL: i32 = 9;
+
+/// content codings a response can be compressed with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+}
+
+impl Encoding {
+    /// parses a content coding as written in config and Accept-Encoding
+    pub(crate) fn from_token(token: &str) -> Option<Self> {
+        match token {
+            "br" => Some(Encoding::Brotli),
+            "zstd" => Some(Encoding::Zstd),
+            "gzip" => Some(Encoding::Gzip),
+            _ => None,
+        }
+    }
+
+    /// name used in Content-Encoding, cache variants and etags
+    pub(crate) fn token(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// extension of precompressed files, app.js.br is app.js compressed with brotli
+    pub(crate) fn file_extension(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zst",
+            Encoding::Gzip => "gz",
+        }
+    }
+
+    /// compresses the data, this is cpu heavy so should be run off the async workers
+    pub(crate) fn compress(&self, data: &[u8]) -> io::Result<Bytes> {
+        let compressed = match self {
+            Encoding::Brotli => {
+                let mut encoder =
+                    brotli::CompressorWriter::new(Vec::new(), 4096, BROTLI_QUALITY, BROTLI_WINDOW);
+                encoder.write_all(data)?;
+                encoder.into_inner()
+            }
+            Encoding::Zstd => zstd::encode_all(data, ZSTD_LEVEL)?,
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::new(GZIP_LEVEL));
+                encoder.write_all(data)?;
+                encoder.finish()?
+            }
+        };
+        Ok(Bytes::from(compressed))
+    }
+}
+
+/// picks the offered encoding with the highest q value in Accept-Encoding, ties going to the
+/// earliest in offered. None means the response is sent uncompressed.
+pub(crate) fn negotiate(headers: &HeaderMap, offered: &[Encoding]) -> Option<Encoding> {
+    let accept_encoding = headers.get(ACCEPT_ENCODING)?.to_str().ok()?;
+
+    let mut wildcard_quality = None;
+    let mut qualities: Vec<(String, f32)> = Vec::new();
+    for coding in accept_encoding.split(',') {
+        let mut parameters = coding.split(';');
+        let token = match parameters.next() {
+            Some(token) if !token.trim().is_empty() => token.trim().to_ascii_lowercase(),
+            _ => continue,
+        };
+        // parameter names are case-insensitive, so Q=0.5 is a quality too
+        let quality = parameters
+            .filter_map(|parameter| {
+                let (name, value) = parameter.split_once('=')?;
+                name.trim().eq_ignore_ascii_case("q").then(|| value.trim())
+            })
+            .find_map(|quality| quality.parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        if token == "*" {
+            wildcard_quality = Some(quality);
+        } else {
+            qualities.push((token, quality));
+        }
+    }
+
+    let mut best: Option<(Encoding, f32)> = None;
+    for encoding in offered {
+        let quality = qualities
+            .iter()
+            .find(|(token, _)| token == encoding.token())
+            .map(|(_, quality)| *quality)
+            .or(wildcard_quality)
+            .unwrap_or(0.0);
+        if quality > 0.0 && best.is_none_or(|(_, best_quality)| quality > best_quality) {
+            best = Some((*encoding, quality));
+        }
+    }
+
+    // a client explicitly preferring identity gets the response uncompressed
+    let identity_quality = qualities
+        .iter()
+        .find(|(token, _)| token == "identity")
+        .map_or(0.0, |(_, quality)| *quality);
+    best.filter(|(_, quality)| *quality >= identity_quality)
+        .map(|(encoding, _)| encoding)
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::header::HeaderValue;
+
+    use super::*;
+
+    const ALL: &[Encoding] = &[Encoding::Brotli, Encoding::Zstd, Encoding::Gzip];
+
+    fn negotiate_with(accept_encoding: &str, offered: &[Encoding]) -> Option<Encoding> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            ACCEPT_ENCODING,
+            HeaderValue::from_str(accept_encoding).unwrap(),
+        );
+        negotiate(&headers, offered)
+    }
+
+    #[test]
+    fn picks_the_highest_quality() {
+        assert_eq!(negotiate(&HeaderMap::new(), ALL), None);
+        assert_eq!(
+            negotiate_with("gzip;q=0.9, zstd;q=0.5", ALL),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(negotiate_with("deflate, compress", ALL), None);
+        assert_eq!(
+            negotiate_with("gzip;q=0, br;q=0.1", ALL),
+            Some(Encoding::Brotli)
+        );
+        assert_eq!(negotiate_with("br", &[Encoding::Gzip]), None);
+    }
+
+    #[test]
+    fn breaks_ties_by_server_preference() {
+        assert_eq!(
+            negotiate_with("gzip, zstd, br", ALL),
+            Some(Encoding::Brotli)
+        );
+        assert_eq!(
+            negotiate_with("gzip, br", &[Encoding::Gzip, Encoding::Brotli]),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(
+            negotiate_with("gzip;q=0.5, zstd;q=0.5, br;q=0.4", ALL),
+            Some(Encoding::Zstd)
+        );
+    }
+
+    #[test]
+    fn honours_identity_quality() {
+        // identity;q=0 forbids an uncompressed response, but only an offered encoding can replace it
+        assert_eq!(
+            negotiate_with("identity;q=0, gzip;q=0.1", ALL),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(negotiate_with("identity;q=0", ALL), None);
+        // identity preferred over every encoding
+        assert_eq!(negotiate_with("identity, gzip;q=0.5", ALL), None);
+        assert_eq!(
+            negotiate_with("identity;q=0.5, gzip", ALL),
+            Some(Encoding::Gzip)
+        );
+    }
+
+    #[test]
+    fn applies_the_wildcard_to_unlisted_encodings() {
+        assert_eq!(negotiate_with("*", ALL), Some(Encoding::Brotli));
+        assert_eq!(negotiate_with("br;q=0, *", ALL), Some(Encoding::Zstd));
+        assert_eq!(negotiate_with("*;q=0", ALL), None);
+        assert_eq!(negotiate_with("gzip, *;q=0", ALL), Some(Encoding::Gzip));
+    }
+
+    #[test]
+    fn reads_tokens_and_parameters_case_insensitively() {
+        assert_eq!(
+            negotiate_with("GZIP;Q=0.9, Br;Q=0.2", ALL),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(negotiate_with("br;Q=0", &[Encoding::Brotli]), None);
+        assert_eq!(
+            negotiate_with("gzip ; q = 0.5 , br;q=0.4", ALL),
+            Some(Encoding::Gzip)
+        );
+    }
+}
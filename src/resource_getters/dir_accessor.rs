@@ -1,60 +1,181 @@
-use std::env;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::time::{Duration, SystemTime};
 
 use chrono::{DateTime, Timelike, Utc};
 use hyper::body::Bytes;
-use hyper::Uri;
 use tokio::fs;
+use tokio::io::AsyncReadExt;
+use tracing::{info, instrument, warn};
+use uuid::Uuid;
 
-// returns the resource, or an error
-// TODO: Make this work for many resources, not just text
-pub(crate) async fn retrieve_resource(uri: &Uri) -> Option<(Bytes, Option<(String, SystemTime)>)> {
-    // check if file exists
-    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-    path.push("resources");
-
-    if uri == "/" {
-        path.push("index.html");
-    } else {
-        let path_uri = match uri.to_string().strip_prefix('/') {
-            Some(stripped_uri) => stripped_uri.to_string(),
-            None => uri.to_string(),
+use crate::config::ResourceConfig;
+use crate::resource_getters::content_encoding::Encoding;
+use crate::resource_getters::mime_types;
+use crate::resource_getters::path_sanitiser::{self, PathError};
+use crate::resource_getters::resource_key::ResourceKey;
+use crate::resource_getters::web_content::ResourceBody;
+
+/// bytes read from a streamed file to sniff its content type
+const SNIFF_LENGTH: usize = 512;
+
+/// maps a resource key to a file path confined to the document root
+pub(crate) fn resolve_path(
+    resource_key: &ResourceKey,
+    resource_config: &ResourceConfig,
+) -> Result<PathBuf, PathError> {
+    path_sanitiser::confine_path(&resource_config.root, resource_key.get_path())
+}
+
+/// first configured index file present in a requested directory.
+/// Candidates are confined like any other path, so a symlinked index can't escape the root.
+pub(crate) fn resolve_index_path(
+    resource_key: &ResourceKey,
+    resource_config: &ResourceConfig,
+) -> Option<PathBuf> {
+    let dir_path = resource_key.get_path().trim_end_matches('/');
+    resource_config.index_files.iter().find_map(|index_file| {
+        path_sanitiser::confine_path(
+            &resource_config.root,
+            &format!("{}/{}", dir_path, index_file),
+        )
+        .ok()
+        .filter(|index_path| index_path.is_file())
+    })
+}
+
+/// precompressed sibling of a file for an encoding, if one exists within the document root
+pub(crate) fn resolve_precompressed_path(
+    path: &Path,
+    encoding: Encoding,
+    resource_config: &ResourceConfig,
+) -> Option<PathBuf> {
+    let mut sibling = path.as_os_str().to_owned();
+    sibling.push(".");
+    sibling.push(encoding.file_extension());
+
+    // canonicalised like request paths so a symlinked sibling can't escape the root
+    PathBuf::from(sibling)
+        .canonicalize()
+        .ok()
+        .filter(|sibling| sibling.starts_with(&resource_config.root) && sibling.is_file())
+}
+
+/// hidden file next to a target that an upload is written to before being renamed into place
+pub(crate) fn staging_path(path: &Path) -> PathBuf {
+    let file_name = path
+        .file_name()
+        .map(|file_name| file_name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!(".{}.{}.tmp", file_name, Uuid::new_v4().simple()))
+}
+
+/// true for a file an upload is being written to, named `.{name}.{uuid}.tmp`.
+/// These are never listed or served, and can't be the target of a write.
+pub(crate) fn is_staging_file(path: &Path) -> bool {
+    let staged = match path
+        .file_name()
+        .and_then(|file_name| file_name.to_str())
+        .and_then(|file_name| file_name.strip_prefix('.'))
+        .and_then(|file_name| file_name.strip_suffix(".tmp"))
+    {
+        Some(staged) => staged,
+        None => return false,
+    };
+
+    match staged.rsplit_once('.') {
+        Some((name, id)) => {
+            !name.is_empty() && id.len() == 32 && id.bytes().all(|byte| byte.is_ascii_hexdigit())
+        }
+        None => false,
+    }
+}
+
+/// removes the staging files of uploads a crash or restart interrupted, in the background.
+/// Only files older than the call are removed, so uploads started meanwhile are left alone.
+pub(crate) fn spawn_staging_cleanup(root: PathBuf) {
+    let started = SystemTime::now();
+    tokio::task::spawn_blocking(move || remove_staging_files(&root, started));
+}
+
+/// walks a directory for staging files modified before the given time
+fn remove_staging_files(dir: &Path, before: SystemTime) {
+    let read_dir = match std::fs::read_dir(dir) {
+        Ok(read_dir) => read_dir,
+        Err(_) => return,
+    };
+
+    for dir_entry in read_dir.flatten() {
+        let path = dir_entry.path();
+        // symlinks aren't followed, so nothing outside the root is touched
+        let file_type = match dir_entry.file_type() {
+            Ok(file_type) => file_type,
+            Err(_) => continue,
         };
+        if file_type.is_dir() {
+            remove_staging_files(&path, before);
+            continue;
+        }
 
-        path.push(path_uri);
+        let stale = dir_entry
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .is_ok_and(|modified| modified < before);
+        if file_type.is_file() && stale && is_staging_file(&path) {
+            match std::fs::remove_file(&path) {
+                Ok(()) => info!(path = %path.display(), "Removed an interrupted upload"),
+                Err(err) => {
+                    warn!(%err, path = %path.display(), "Could not remove an interrupted upload")
+                }
+            }
+        }
     }
+}
 
+// returns the resource, or an error. Files above the stream threshold are not read, only their first block
+#[instrument(name = "disk_read", level = "debug", skip_all, fields(path = %path.display()))]
+pub(crate) async fn retrieve_resource(
+    path: &Path,
+    resource_config: &ResourceConfig,
+) -> Option<(ResourceBody, Option<(String, SystemTime)>)> {
+    // check if file exists, uploads still being written are never served
     let path_exists = match path.try_exists() {
-        Ok(path_exists) => path_exists,
+        Ok(path_exists) => path_exists && !is_staging_file(path),
         Err(_) => return None,
     };
 
     match path_exists {
         true => {
-            // read the content and get the last modified in SystemTime
-            let resource_content = match fs::read(&path).await {
-                Ok(resource_content) => resource_content,
+            // get the length and last modified in SystemTime
+            let metadata = match fs::metadata(path).await {
+                Ok(metadata) => metadata,
                 Err(_) => return None,
             };
-
-            let last_modified = match fs::metadata(&path).await {
-                Ok(metadata) => match metadata.modified() {
-                    Ok(last_modified) => last_modified,
-                    Err(_) => return None,
-                },
+            let last_modified = match metadata.modified() {
+                Ok(last_modified) => last_modified,
                 Err(_) => return None,
             };
 
-            let content_type = match &path.extension() {
-                Some(extension) => match extension.to_string_lossy().to_lowercase().as_str() {
-                    "html" => "text/html; charset=utf-8",
-                    "ico" => "image/x-icon",
-                    _ => return None,
-                },
-                None => return None,
-            }
-            .to_string();
+            // read the content, or just enough of a large file to sniff its type
+            let (resource_body, sniffed_content) =
+                if metadata.len() > resource_config.stream_threshold_bytes {
+                    let mut head = vec![0; SNIFF_LENGTH];
+                    let mut file = fs::File::open(path).await.ok()?;
+                    let read_length = file.read(&mut head).await.ok()?;
+                    head.truncate(read_length);
+                    let resource_body = ResourceBody::File {
+                        path: path.to_path_buf(),
+                        length: metadata.len(),
+                    };
+                    (resource_body, Bytes::from(head))
+                } else {
+                    let resource_content = Bytes::from(fs::read(path).await.ok()?);
+                    (
+                        ResourceBody::InMemory(resource_content.clone()),
+                        resource_content,
+                    )
+                };
+
+            let content_type = mime_types::content_type(path, &sniffed_content, resource_config);
 
             // convert SystemTime to DateTime then round/convert back
             let datetime_last_mod: DateTime<Utc> = DateTime::from(last_modified);
@@ -63,24 +184,71 @@ pub(crate) async fn retrieve_resource(uri: &Uri) -> Option<(Bytes, Option<(Strin
                 SystemTime::UNIX_EPOCH + Duration::new(datetime_trunc.timestamp() as u64, 0);
 
             // form tuple and send off
-            let return_data = (
-                Bytes::from(resource_content),
-                Some((content_type, last_modified_rounded)),
-            );
+            let return_data = (resource_body, Some((content_type, last_modified_rounded)));
             Some(return_data)
         }
         false => {
-            let mut not_found_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-            not_found_path.push("resources");
-            not_found_path.push("404.html");
+            let resource_content = retrieve_not_found_page(resource_config).await?;
+            Some((ResourceBody::InMemory(resource_content), None))
+        }
+    }
+}
 
-            let resource_content = match fs::read(not_found_path).await {
-                Ok(resource_content) => resource_content,
-                Err(_) => return None,
-            };
+/// reads the configured 404 page
+pub(crate) async fn retrieve_not_found_page(resource_config: &ResourceConfig) -> Option<Bytes> {
+    fs::read(&resource_config.not_found_page)
+        .await
+        .ok()
+        .map(Bytes::from)
+}
 
-            let return_data = (Bytes::from(resource_content), None);
-            Some(return_data)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognises_staging_names() {
+        let staging_path = staging_path(Path::new("/root/notes.txt"));
+        assert_eq!(staging_path.parent(), Some(Path::new("/root")));
+        assert!(is_staging_file(&staging_path));
+
+        for not_staging in [
+            "/root/notes.txt",
+            "/root/.notes.txt",
+            "/root/.notes.txt.tmp",
+            "/root/notes.0123456789abcdef0123456789abcdef.tmp",
+            "/root/..0123456789abcdef0123456789abcdef.tmp",
+            "/root/.notes.0123456789abcdef0123456789abcdeg.tmp",
+            "/root/.notes.0123456789abcdef.tmp",
+        ] {
+            assert!(!is_staging_file(Path::new(not_staging)), "{}", not_staging);
         }
     }
+
+    #[test]
+    fn removes_interrupted_uploads_only() {
+        let outside = tempfile::tempdir().unwrap();
+        let outside_staging = outside
+            .path()
+            .join(".kept.0123456789abcdef0123456789abcdef.tmp");
+        std::fs::write(&outside_staging, "elsewhere").unwrap();
+
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("nested")).unwrap();
+        let interrupted = staging_path(&root.path().join("nested").join("upload.bin"));
+        std::fs::write(&interrupted, "partial").unwrap();
+        std::fs::write(root.path().join("page.tmp"), "kept").unwrap();
+        std::os::unix::fs::symlink(outside.path(), root.path().join("link")).unwrap();
+
+        remove_staging_files(root.path(), SystemTime::now() + Duration::from_secs(1));
+        assert!(!interrupted.exists());
+        assert!(root.path().join("page.tmp").exists());
+        assert!(outside_staging.exists());
+
+        // uploads started after the sweep began are left alone
+        let in_progress = staging_path(&root.path().join("upload.bin"));
+        std::fs::write(&in_progress, "partial").unwrap();
+        remove_staging_files(root.path(), SystemTime::now() - Duration::from_secs(60));
+        assert!(in_progress.exists());
+    }
 }
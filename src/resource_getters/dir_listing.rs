@@ -0,0 +1,479 @@
+use std::cmp::Ordering;
+use std::fmt::Write;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use hyper::body::Bytes;
+use hyper::header::ACCEPT;
+use hyper::Request;
+use serde_json::json;
+use tokio::fs;
+
+use crate::resource_getters::{dir_accessor, path_sanitiser};
+
+const HTML_CONTENT_TYPE: &str = "text/html; charset=utf-8";
+const JSON_CONTENT_TYPE: &str = "application/json";
+
+/// representation of the listing, picked from the Accept header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ListingFormat {
+    Html,
+    Json,
+}
+
+/// column the listing is sorted by, picked from the sort query parameter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SortKey {
+    Name,
+    Size,
+    Modified,
+}
+
+/// how a listing is rendered, each combination is cached as its own variant
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ListingOptions {
+    format: ListingFormat,
+    sort_key: SortKey,
+    descending: bool,
+}
+
+struct DirectoryEntry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: SystemTime,
+}
+
+impl ListingOptions {
+    /// reads the format from Accept and the order from the ?sort=name|size|modified&order=asc|desc query
+    pub(crate) fn from_request<B>(req: &Request<B>) -> Self {
+        let mut options = Self {
+            format: ListingFormat::Html,
+            sort_key: SortKey::Name,
+            descending: false,
+        };
+
+        if let Some(accept) = req
+            .headers()
+            .get(ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+        {
+            if accept_quality(accept, "application/json") > accept_quality(accept, "text/html") {
+                options.format = ListingFormat::Json;
+            }
+        }
+
+        for (name, value) in req
+            .uri()
+            .query()
+            .unwrap_or("")
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+        {
+            match (name, value) {
+                ("sort", "name") => options.sort_key = SortKey::Name,
+                ("sort", "size") => options.sort_key = SortKey::Size,
+                ("sort", "modified") => options.sort_key = SortKey::Modified,
+                ("order", "asc") => options.descending = false,
+                ("order", "desc") => options.descending = true,
+                _ => {}
+            }
+        }
+
+        options
+    }
+
+    /// cache variant name for this combination of options
+    pub(crate) fn variant(&self) -> String {
+        format!(
+            "autoindex-{}-{}-{}",
+            match self.format {
+                ListingFormat::Html => "html",
+                ListingFormat::Json => "json",
+            },
+            self.sort_key_name(self.sort_key),
+            if self.descending { "desc" } else { "asc" }
+        )
+    }
+
+    fn sort_key_name(&self, sort_key: SortKey) -> &'static str {
+        match sort_key {
+            SortKey::Name => "name",
+            SortKey::Size => "size",
+            SortKey::Modified => "modified",
+        }
+    }
+}
+
+/// renders the listing of a directory, returning the body, its content type and the newest modification time.
+/// Entries starting with a dot are left out unless show_hidden is set, uploads in progress always are.
+pub(crate) async fn render_listing(
+    dir: &Path,
+    request_path: &str,
+    options: &ListingOptions,
+    show_hidden: bool,
+) -> Option<(Bytes, String, SystemTime)> {
+    let mut last_modified = fs::metadata(dir).await.ok()?.modified().ok()?;
+    let mut entries = Vec::new();
+
+    let mut read_dir = fs::read_dir(dir).await.ok()?;
+    while let Some(dir_entry) = read_dir.next_entry().await.ok()? {
+        let name = match dir_entry.file_name().into_string() {
+            Ok(name) => name,
+            // names that aren't utf-8 can't be requested anyway
+            Err(_) => continue,
+        };
+        if (name.starts_with('.') && !show_hidden)
+            || dir_accessor::is_staging_file(&dir_entry.path())
+        {
+            continue;
+        }
+
+        // follow symlinks so linked files show their real size
+        let metadata = match fs::metadata(dir_entry.path()).await {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+        last_modified = last_modified.max(modified);
+
+        entries.push(DirectoryEntry {
+            name,
+            is_dir: metadata.is_dir(),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified,
+        });
+    }
+
+    sort_entries(&mut entries, options);
+
+    let (body, content_type) = match options.format {
+        ListingFormat::Html => (
+            render_html(request_path, &entries, options),
+            HTML_CONTENT_TYPE,
+        ),
+        ListingFormat::Json => (render_json(request_path, &entries), JSON_CONTENT_TYPE),
+    };
+
+    // http dates have second precision, round down so conditional requests compare equal
+    let last_modified_rounded = last_modified
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|since_epoch| SystemTime::UNIX_EPOCH + Duration::from_secs(since_epoch.as_secs()))
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+
+    Some((
+        Bytes::from(body),
+        content_type.to_string(),
+        last_modified_rounded,
+    ))
+}
+
+/// directories first, then by the chosen column with the name breaking ties
+fn sort_entries(entries: &mut [DirectoryEntry], options: &ListingOptions) {
+    entries.sort_by(|first, second| {
+        let ordering = match options.sort_key {
+            SortKey::Name => Ordering::Equal,
+            SortKey::Size => first.size.cmp(&second.size),
+            SortKey::Modified => first.modified.cmp(&second.modified),
+        }
+        .then_with(|| first.name.cmp(&second.name));
+
+        let ordering = if options.descending {
+            ordering.reverse()
+        } else {
+            ordering
+        };
+        second.is_dir.cmp(&first.is_dir).then(ordering)
+    });
+}
+
+fn render_html(request_path: &str, entries: &[DirectoryEntry], options: &ListingOptions) -> String {
+    let title = format!("Index of {}", escape_html(request_path));
+    let mut html = String::new();
+
+    let _ = write!(
+        html,
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>{title}</title>\n</head>\n<body>\n<h1>{title}</h1>\n<table>\n<tr>"
+    );
+
+    // clicking the current sort column flips the order
+    for (sort_key, heading) in [
+        (SortKey::Name, "Name"),
+        (SortKey::Size, "Size"),
+        (SortKey::Modified, "Last modified"),
+    ] {
+        let order = if sort_key == options.sort_key && !options.descending {
+            "desc"
+        } else {
+            "asc"
+        };
+        let _ = write!(
+            html,
+            "<th><a href=\"?sort={}&amp;order={}\">{}</a></th>",
+            options.sort_key_name(sort_key),
+            order,
+            heading
+        );
+    }
+    html.push_str("</tr>\n");
+
+    // links are absolute so they work whether or not the request had a trailing slash
+    let base = path_sanitiser::encode_path(request_path.trim_end_matches('/'));
+    if let Some((parent, _)) = base.rsplit_once('/') {
+        let _ = writeln!(
+            html,
+            "<tr><td><a href=\"{}/\">../</a></td><td>-</td><td>-</td></tr>",
+            parent
+        );
+    }
+
+    for entry in entries {
+        let suffix = if entry.is_dir { "/" } else { "" };
+        let size = if entry.is_dir {
+            "-".to_string()
+        } else {
+            entry.size.to_string()
+        };
+        let _ = writeln!(
+            html,
+            "<tr><td><a href=\"{}/{}{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>",
+            base,
+            path_sanitiser::encode_path(&entry.name),
+            suffix,
+            escape_html(&entry.name),
+            suffix,
+            size,
+            DateTime::<Utc>::from(entry.modified).format("%Y-%m-%d %H:%M:%S")
+        );
+    }
+
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+fn render_json(request_path: &str, entries: &[DirectoryEntry]) -> String {
+    let entries: Vec<_> = entries
+        .iter()
+        .map(|entry| {
+            json!({
+                "name": entry.name,
+                "type": if entry.is_dir { "directory" } else { "file" },
+                "size": entry.size,
+                "modified": DateTime::<Utc>::from(entry.modified)
+                    .to_rfc3339_opts(SecondsFormat::Secs, true),
+            })
+        })
+        .collect();
+
+    json!({ "path": request_path, "entries": entries }).to_string()
+}
+
+/// q value the Accept header gives a media type, 0 if it isn't acceptable
+fn accept_quality(accept: &str, media_type: &str) -> f32 {
+    let (main_type, _) = media_type.split_once('/').unwrap_or((media_type, ""));
+
+    accept
+        .split(',')
+        .filter_map(|range| {
+            let mut parameters = range.split(';');
+            let range_type = parameters.next()?.trim().to_ascii_lowercase();
+            let matches = range_type == media_type
+                || range_type == "*/*"
+                || range_type == format!("{}/*", main_type);
+            if !matches {
+                return None;
+            }
+
+            let quality = parameters
+                .filter_map(|parameter| {
+                    let (name, value) = parameter.split_once('=')?;
+                    name.trim().eq_ignore_ascii_case("q").then(|| value.trim())
+                })
+                .find_map(|quality| quality.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            // exact matches take precedence over wildcards with the same q value
+            let specificity = if range_type == media_type {
+                0.0001
+            } else {
+                0.0
+            };
+            Some(quality + specificity)
+        })
+        .fold(0.0, f32::max)
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for character in text.chars() {
+        match character {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(character),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCRIPT_NAME: &str = "<script>&\"quote'.txt";
+
+    fn options(accept: Option<&str>, query: &str) -> ListingOptions {
+        let mut builder = Request::builder().uri(format!("/files/{}", query));
+        if let Some(accept) = accept {
+            builder = builder.header(ACCEPT, accept);
+        }
+        ListingOptions::from_request(&builder.body(()).unwrap())
+    }
+
+    /// a directory of files with distinct sizes, a subdirectory, a hidden file and an upload in progress
+    fn directory() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "0123456789").unwrap();
+        std::fs::write(dir.path().join("b.txt"), "012").unwrap();
+        std::fs::write(dir.path().join(SCRIPT_NAME), "01234").unwrap();
+        std::fs::write(dir.path().join(".hidden"), "").unwrap();
+        std::fs::write(
+            dir.path()
+                .join(".a.txt.0123456789abcdef0123456789abcdef.tmp"),
+            "",
+        )
+        .unwrap();
+        std::fs::create_dir(dir.path().join("zdir")).unwrap();
+        dir
+    }
+
+    async fn listed_names(dir: &Path, options: &ListingOptions, show_hidden: bool) -> Vec<String> {
+        let json_options = ListingOptions {
+            format: ListingFormat::Json,
+            ..*options
+        };
+        let (body, content_type, _) = render_listing(dir, "/files/", &json_options, show_hidden)
+            .await
+            .unwrap();
+        assert_eq!(content_type, JSON_CONTENT_TYPE);
+        let listing: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(listing["path"], "/files/");
+        listing["entries"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| entry["name"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn picks_the_format_from_accept() {
+        for (accept, format) in [
+            (None, ListingFormat::Html),
+            (Some("application/json"), ListingFormat::Json),
+            (Some("text/html, application/json"), ListingFormat::Html),
+            (
+                Some("text/html;q=0.9, application/json"),
+                ListingFormat::Json,
+            ),
+            (
+                Some("application/json;Q=0.5, text/html"),
+                ListingFormat::Html,
+            ),
+            (Some("application/*"), ListingFormat::Json),
+            (Some("*/*"), ListingFormat::Html),
+            (
+                Some("*/*;q=0.8, application/json;q=0.8"),
+                ListingFormat::Json,
+            ),
+            (Some("image/png"), ListingFormat::Html),
+            (
+                Some("text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"),
+                ListingFormat::Html,
+            ),
+        ] {
+            assert_eq!(options(accept, "").format, format, "{:?}", accept);
+        }
+    }
+
+    #[test]
+    fn reads_the_order_from_the_query() {
+        for (query, variant) in [
+            ("", "autoindex-html-name-asc"),
+            ("?sort=size", "autoindex-html-size-asc"),
+            ("?sort=modified&order=desc", "autoindex-html-modified-desc"),
+            ("?order=desc&sort=bogus", "autoindex-html-name-desc"),
+            (
+                "?sort=size&sort=name&order=desc&order=asc",
+                "autoindex-html-name-asc",
+            ),
+            ("?sort&order=DESC&other=1", "autoindex-html-name-asc"),
+        ] {
+            assert_eq!(options(None, query).variant(), variant, "{}", query);
+        }
+        assert_eq!(
+            options(Some("application/json"), "?order=desc").variant(),
+            "autoindex-json-name-desc"
+        );
+    }
+
+    #[tokio::test]
+    async fn sorts_directories_first_then_by_the_chosen_column() {
+        let dir = directory();
+        assert_eq!(
+            listed_names(dir.path(), &options(None, ""), false).await,
+            ["zdir", SCRIPT_NAME, "a.txt", "b.txt"]
+        );
+        assert_eq!(
+            listed_names(dir.path(), &options(None, "?order=desc"), false).await,
+            ["zdir", "b.txt", "a.txt", SCRIPT_NAME]
+        );
+        assert_eq!(
+            listed_names(dir.path(), &options(None, "?sort=size&order=desc"), false).await,
+            ["zdir", "a.txt", SCRIPT_NAME, "b.txt"]
+        );
+    }
+
+    #[tokio::test]
+    async fn shows_hidden_files_only_when_enabled() {
+        let dir = directory();
+        let names = listed_names(dir.path(), &options(None, ""), false).await;
+        assert!(!names.iter().any(|name| name.starts_with('.')));
+
+        // uploads in progress stay hidden either way
+        let names = listed_names(dir.path(), &options(None, ""), true).await;
+        assert_eq!(names, ["zdir", ".hidden", SCRIPT_NAME, "a.txt", "b.txt"]);
+    }
+
+    #[tokio::test]
+    async fn escapes_names_in_html_listings() {
+        let dir = directory();
+        let (body, content_type, _) =
+            render_listing(dir.path(), "/<files>/", &options(None, ""), false)
+                .await
+                .unwrap();
+        assert_eq!(content_type, HTML_CONTENT_TYPE);
+        let html = String::from_utf8(body.to_vec()).unwrap();
+
+        assert!(!html.contains("<script>"));
+        assert!(!html.contains("<files>"));
+        assert!(html.contains("<title>Index of /&lt;files&gt;/</title>"));
+        assert!(html.contains(
+            "<a href=\"/%3Cfiles%3E/%3Cscript%3E%26%22quote%27.txt\">\
+             &lt;script&gt;&amp;&quot;quote&#39;.txt</a>"
+        ));
+        assert!(html.contains("<a href=\"/%3Cfiles%3E/zdir/\">zdir/</a>"));
+    }
+
+    #[test]
+    fn escapes_markup_characters() {
+        assert_eq!(
+            escape_html("<script>alert('x & \"y\"')</script>"),
+            "&lt;script&gt;alert(&#39;x &amp; &quot;y&quot;&#39;)&lt;/script&gt;"
+        );
+        assert_eq!(escape_html("plain name.txt"), "plain name.txt");
+    }
+}
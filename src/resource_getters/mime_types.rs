@@ -0,0 +1,406 @@
+use std::path::Path;
+
+use crate::config::ResourceConfig;
+
+/// type sent when nothing better is known about a file
+const FALLBACK_TYPE: &str = "application/octet-stream";
+
+/// charset appended to textual types that don't already declare one
+const TEXT_CHARSET: &str = "utf-8";
+
+/// extension to mime type registry, extensions are lowercase and without the dot
+const MIME_TYPES: &[(&str, &str)] = &[
+    // web documents and code
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("xhtml", "application/xhtml+xml"),
+    ("css", "text/css"),
+    ("js", "text/javascript"),
+    ("mjs", "text/javascript"),
+    ("cjs", "text/javascript"),
+    ("map", "application/json"),
+    ("json", "application/json"),
+    ("jsonld", "application/ld+json"),
+    ("webmanifest", "application/manifest+json"),
+    ("xml", "application/xml"),
+    ("xsl", "application/xml"),
+    ("rss", "application/rss+xml"),
+    ("atom", "application/atom+xml"),
+    ("wasm", "application/wasm"),
+    // plain text and data
+    ("txt", "text/plain"),
+    ("text", "text/plain"),
+    ("log", "text/plain"),
+    ("md", "text/markdown"),
+    ("markdown", "text/markdown"),
+    ("csv", "text/csv"),
+    ("tsv", "text/tab-separated-values"),
+    ("ics", "text/calendar"),
+    ("vtt", "text/vtt"),
+    ("yaml", "application/yaml"),
+    ("yml", "application/yaml"),
+    ("toml", "application/toml"),
+    // images
+    ("ico", "image/x-icon"),
+    ("cur", "image/x-icon"),
+    ("png", "image/png"),
+    ("apng", "image/apng"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("jfif", "image/jpeg"),
+    ("pjpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    // svgz is left out, it is gzip data that only renders when sent with Content-Encoding: gzip
+    ("svg", "image/svg+xml"),
+    ("bmp", "image/bmp"),
+    ("tif", "image/tiff"),
+    ("tiff", "image/tiff"),
+    ("heic", "image/heic"),
+    ("heif", "image/heif"),
+    ("jxl", "image/jxl"),
+    // fonts
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("ttc", "font/collection"),
+    ("eot", "application/vnd.ms-fontobject"),
+    // audio
+    ("mp3", "audio/mpeg"),
+    ("m4a", "audio/mp4"),
+    ("aac", "audio/aac"),
+    ("oga", "audio/ogg"),
+    ("ogg", "audio/ogg"),
+    ("opus", "audio/opus"),
+    ("wav", "audio/wav"),
+    ("weba", "audio/webm"),
+    ("flac", "audio/flac"),
+    ("mid", "audio/midi"),
+    ("midi", "audio/midi"),
+    // video
+    ("mp4", "video/mp4"),
+    ("m4v", "video/mp4"),
+    ("webm", "video/webm"),
+    ("ogv", "video/ogg"),
+    ("mov", "video/quicktime"),
+    ("avi", "video/x-msvideo"),
+    ("mpeg", "video/mpeg"),
+    ("mpg", "video/mpeg"),
+    ("mkv", "video/x-matroska"),
+    ("ts", "video/mp2t"),
+    ("m3u8", "application/vnd.apple.mpegurl"),
+    ("mpd", "application/dash+xml"),
+    // archives and compressed data
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tgz", "application/gzip"),
+    ("tar", "application/x-tar"),
+    ("bz2", "application/x-bzip2"),
+    ("xz", "application/x-xz"),
+    ("zst", "application/zstd"),
+    ("br", "application/x-brotli"),
+    ("7z", "application/x-7z-compressed"),
+    ("rar", "application/vnd.rar"),
+    ("jar", "application/java-archive"),
+    // documents
+    ("pdf", "application/pdf"),
+    ("rtf", "application/rtf"),
+    ("doc", "application/msword"),
+    (
+        "docx",
+        "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+    ),
+    ("xls", "application/vnd.ms-excel"),
+    (
+        "xlsx",
+        "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+    ),
+    ("ppt", "application/vnd.ms-powerpoint"),
+    (
+        "pptx",
+        "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+    ),
+    ("odt", "application/vnd.oasis.opendocument.text"),
+    ("ods", "application/vnd.oasis.opendocument.spreadsheet"),
+    ("odp", "application/vnd.oasis.opendocument.presentation"),
+    ("epub", "application/epub+zip"),
+    // binaries and misc
+    ("bin", "application/octet-stream"),
+    ("exe", "application/octet-stream"),
+    ("dll", "application/octet-stream"),
+    ("iso", "application/octet-stream"),
+    ("dmg", "application/octet-stream"),
+    ("deb", "application/vnd.debian.binary-package"),
+    ("rpm", "application/x-rpm"),
+    ("apk", "application/vnd.android.package-archive"),
+    ("sh", "application/x-sh"),
+    ("ps", "application/postscript"),
+];
+
+/// non text/* types whose content is text and so should carry a charset
+const TEXTUAL_APPLICATION_TYPES: &[&str] = &[
+    "application/json",
+    "application/ld+json",
+    "application/manifest+json",
+    "application/xml",
+    "application/xhtml+xml",
+    "application/rss+xml",
+    "application/atom+xml",
+    "application/yaml",
+    "application/toml",
+    "image/svg+xml",
+];
+
+/// binary types that compress well
+const COMPRESSIBLE_BINARY_TYPES: &[&str] = &[
+    "application/wasm",
+    "font/ttf",
+    "font/otf",
+    "image/bmp",
+    "image/x-icon",
+];
+
+/// works out the Content-Type for a file from its extension, configured overrides
+/// and (for extensionless files, when enabled) its leading bytes
+pub(crate) fn content_type(path: &Path, data: &[u8], resource_config: &ResourceConfig) -> String {
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+
+    let mime_type = match extension {
+        Some(extension) => match resource_config.mime_types.get(&extension) {
+            Some(override_type) => override_type.as_str(),
+            None => lookup_extension(&extension).unwrap_or(FALLBACK_TYPE),
+        },
+        None if resource_config.sniff_extensionless => sniff(data).unwrap_or(FALLBACK_TYPE),
+        None => FALLBACK_TYPE,
+    };
+
+    with_charset(mime_type)
+}
+
+/// finds the registered type for a lowercase extension
+fn lookup_extension(extension: &str) -> Option<&'static str> {
+    MIME_TYPES
+        .iter()
+        .find(|(registered, _)| *registered == extension)
+        .map(|(_, mime_type)| *mime_type)
+}
+
+/// true for types worth compressing, text and a few binary formats that aren't compressed already
+pub(crate) fn is_compressible(content_type: &str) -> bool {
+    let essence = essence(content_type);
+    is_textual(&essence) || COMPRESSIBLE_BINARY_TYPES.contains(&essence.as_str())
+}
+
+/// adds a charset parameter to textual types that don't already have one
+fn with_charset(mime_type: &str) -> String {
+    if is_textual(&essence(mime_type)) && !mime_type.to_ascii_lowercase().contains("charset=") {
+        format!("{}; charset={}", mime_type, TEXT_CHARSET)
+    } else {
+        mime_type.to_string()
+    }
+}
+
+/// lowercased type without parameters
+fn essence(mime_type: &str) -> String {
+    mime_type
+        .split(';')
+        .next()
+        .unwrap_or(mime_type)
+        .trim()
+        .to_ascii_lowercase()
+}
+
+fn is_textual(essence: &str) -> bool {
+    essence.starts_with("text/") || TEXTUAL_APPLICATION_TYPES.contains(&essence)
+}
+
+/// guesses a type from magic numbers, falling back to text/plain for readable utf-8
+fn sniff(data: &[u8]) -> Option<&'static str> {
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x1f\x8b", "application/gzip"),
+        (b"\x28\xb5\x2f\xfd", "application/zstd"),
+        (b"\0asm", "application/wasm"),
+        (b"\0\0\x01\0", "image/x-icon"),
+        (b"wOFF", "font/woff"),
+        (b"wOF2", "font/woff2"),
+        (b"OggS", "audio/ogg"),
+        (b"ID3", "audio/mpeg"),
+        (b"\x1a\x45\xdf\xa3", "video/webm"),
+    ];
+
+    if let Some((_, mime_type)) = SIGNATURES
+        .iter()
+        .find(|(signature, _)| data.starts_with(signature))
+    {
+        return Some(mime_type);
+    }
+
+    // container formats with the signature at an offset
+    if data.len() >= 12 && &data[0..4] == b"RIFF" {
+        match &data[8..12] {
+            b"WEBP" => return Some("image/webp"),
+            b"WAVE" => return Some("audio/wav"),
+            b"AVI " => return Some("video/x-msvideo"),
+            _ => {}
+        }
+    }
+    if data.len() >= 12 && &data[4..8] == b"ftyp" {
+        return match &data[8..12] {
+            b"avif" => Some("image/avif"),
+            b"heic" => Some("image/heic"),
+            _ => Some("video/mp4"),
+        };
+    }
+
+    // textual content, only the first block is checked
+    let head = &data[..data.len().min(512)];
+    let text = match std::str::from_utf8(head) {
+        Ok(text) => text,
+        // the block may have cut a multi-byte character in half
+        Err(err) if err.error_len().is_none() => {
+            std::str::from_utf8(&head[..err.valid_up_to()]).ok()?
+        }
+        Err(_) => return None,
+    };
+
+    let lowercase_start = text.trim_start().to_ascii_lowercase();
+    if lowercase_start.starts_with("<!doctype html") || lowercase_start.starts_with("<html") {
+        Some("text/html")
+    } else if lowercase_start.starts_with("<?xml") {
+        Some("application/xml")
+    } else if lowercase_start.starts_with("<svg") {
+        Some("image/svg+xml")
+    } else if text.chars().all(|character| {
+        !character.is_control() || matches!(character, '\t' | '\r' | '\n' | '\x0c')
+    }) {
+        Some("text/plain")
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn resource_config(overrides: &[(&str, &str)], sniff_extensionless: bool) -> ResourceConfig {
+        ResourceConfig {
+            mime_types: overrides
+                .iter()
+                .map(|(extension, mime_type)| (extension.to_string(), mime_type.to_string()))
+                .collect::<HashMap<_, _>>(),
+            sniff_extensionless,
+            ..ResourceConfig::default()
+        }
+    }
+
+    #[test]
+    fn looks_up_extensions_case_insensitively() {
+        let config = resource_config(&[], false);
+        for (path, expected) in [
+            ("index.html", "text/html; charset=utf-8"),
+            ("INDEX.HTM", "text/html; charset=utf-8"),
+            ("app.min.js", "text/javascript; charset=utf-8"),
+            ("data.json", "application/json; charset=utf-8"),
+            ("logo.svg", "image/svg+xml; charset=utf-8"),
+            ("photo.JPG", "image/jpeg"),
+            ("font.woff2", "font/woff2"),
+            ("archive.tar.gz", "application/gzip"),
+            ("logo.svgz", FALLBACK_TYPE),
+            ("unknown.xyz", FALLBACK_TYPE),
+            ("README", FALLBACK_TYPE),
+        ] {
+            assert_eq!(
+                content_type(Path::new(path), b"", &config),
+                expected,
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn overrides_replace_registered_types() {
+        let config = resource_config(
+            &[
+                ("js", "application/javascript"),
+                ("data", "text/plain; charset=latin1"),
+                ("svgz", "image/svg+xml"),
+            ],
+            false,
+        );
+        assert_eq!(
+            content_type(Path::new("app.js"), b"", &config),
+            "application/javascript"
+        );
+        assert_eq!(
+            content_type(Path::new("notes.DATA"), b"", &config),
+            "text/plain; charset=latin1"
+        );
+        assert_eq!(
+            content_type(Path::new("page.html"), b"", &config),
+            "text/html; charset=utf-8"
+        );
+    }
+
+    #[test]
+    fn sniffs_extensionless_files_only_when_enabled() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        assert_eq!(
+            content_type(Path::new("image"), png, &resource_config(&[], false)),
+            FALLBACK_TYPE
+        );
+        assert_eq!(
+            content_type(Path::new("image"), png, &resource_config(&[], true)),
+            "image/png"
+        );
+        // an extension always wins over the content
+        assert_eq!(
+            content_type(Path::new("image.txt"), png, &resource_config(&[], true)),
+            "text/plain; charset=utf-8"
+        );
+    }
+
+    #[test]
+    fn sniffs_magic_numbers_and_text() {
+        assert_eq!(sniff(b"GIF89a\x01\0"), Some("image/gif"));
+        assert_eq!(sniff(b"\x1f\x8b\x08\0"), Some("application/gzip"));
+        assert_eq!(sniff(b"RIFF\0\0\0\0WEBPVP8 "), Some("image/webp"));
+        assert_eq!(sniff(b"\0\0\0\x20ftypavif"), Some("image/avif"));
+        assert_eq!(sniff(b"\0\0\0\x20ftypisom"), Some("video/mp4"));
+        assert_eq!(sniff(b"  <!DOCTYPE html><p>"), Some("text/html"));
+        assert_eq!(sniff(b"<?xml version=\"1.0\"?>"), Some("application/xml"));
+        assert_eq!(sniff(b"<svg xmlns=\"\">"), Some("image/svg+xml"));
+        assert_eq!(sniff(b"plain\ttext\r\n"), Some("text/plain"));
+        assert_eq!(sniff(b"binary\0data"), None);
+        assert_eq!(sniff(&[0xff, 0xfe, 0x00]), None);
+
+        // a multi-byte character cut off at the end of the sniffed block is still text
+        let mut text = "a".repeat(511).into_bytes();
+        text.extend_from_slice("é".as_bytes());
+        assert_eq!(sniff(&text), Some("text/plain"));
+    }
+
+    #[test]
+    fn compresses_text_and_uncompressed_binaries() {
+        assert!(is_compressible("text/html; charset=utf-8"));
+        assert!(is_compressible("image/svg+xml; charset=utf-8"));
+        assert!(is_compressible("Application/JSON"));
+        assert!(is_compressible("application/wasm"));
+        assert!(!is_compressible("image/png"));
+        assert!(!is_compressible("application/gzip"));
+        assert!(!is_compressible(FALLBACK_TYPE));
+    }
+}
@@ -1,2 +1,7 @@
+pub mod content_encoding;
 pub mod dir_accessor;
+pub mod dir_listing;
+pub mod mime_types;
+pub mod path_sanitiser;
+pub mod resource_key;
 pub mod web_content;
@@ -0,0 +1,194 @@
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// reasons a request path can be refused before touching the filesystem
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathError {
+    /// the path could not be decoded or contains forbidden characters (400)
+    Malformed,
+    /// the path resolves outside of the document root (403)
+    Forbidden,
+}
+
+/// percent-decodes a request path and removes its dot segments.
+/// The result always starts with '/' and keeps a trailing '/' if the request had one.
+pub(crate) fn normalise_path(raw_path: &str) -> Result<String, PathError> {
+    if !raw_path.starts_with('/') {
+        return Err(PathError::Malformed);
+    }
+
+    let decoded_bytes = percent_decode(raw_path)?;
+    let decoded = String::from_utf8(decoded_bytes).map_err(|_| PathError::Malformed)?;
+
+    // NUL bytes truncate paths in C APIs and backslashes are separators on windows
+    if decoded.contains('\0') || decoded.contains('\\') {
+        return Err(PathError::Malformed);
+    }
+
+    // remove dot segments, refusing to climb above the root
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                if segments.pop().is_none() {
+                    return Err(PathError::Forbidden);
+                }
+            }
+            _ => segments.push(segment),
+        }
+    }
+
+    let mut normalised = format!("/{}", segments.join("/"));
+    if !segments.is_empty()
+        && (decoded.ends_with('/') || decoded.ends_with("/.") || decoded.ends_with("/.."))
+    {
+        normalised.push('/');
+    }
+    Ok(normalised)
+}
+
+/// joins a normalised path onto the (canonical) root and checks the real location stays inside it
+pub(crate) fn confine_path(root: &Path, normalised_path: &str) -> Result<PathBuf, PathError> {
+    let path = root.join(normalised_path.trim_start_matches('/'));
+
+    // canonicalising resolves symlinks, so a link pointing out of the root is caught here
+    match path.canonicalize() {
+        Ok(canonical_path) if canonical_path.starts_with(root) => Ok(canonical_path),
+        Ok(_) => Err(PathError::Forbidden),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(path),
+        Err(_) => Err(PathError::Forbidden),
+    }
+}
+
+/// percent-encodes a normalised path for use in links and Location headers, keeping the '/' separators
+pub(crate) fn encode_path(normalised_path: &str) -> String {
+    let mut encoded = String::with_capacity(normalised_path.len());
+    for byte in normalised_path.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'/' | b'-' | b'.' | b'_' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+/// decodes %XX escapes, rejecting truncated or non-hex escapes
+fn percent_decode(raw_path: &str) -> Result<Vec<u8>, PathError> {
+    let bytes = raw_path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            let high = bytes.get(index + 1).and_then(|byte| hex_value(*byte));
+            let low = bytes.get(index + 2).and_then(|byte| hex_value(*byte));
+            match (high, low) {
+                (Some(high), Some(low)) => decoded.push(high << 4 | low),
+                _ => return Err(PathError::Malformed),
+            }
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+    Ok(decoded)
+}
+
+/// value of a single hex digit
+fn hex_value(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removes_dot_segments() {
+        assert_eq!(normalise_path("/a/./b/../c"), Ok("/a/c".to_string()));
+        assert_eq!(normalise_path("//a///b"), Ok("/a/b".to_string()));
+        assert_eq!(normalise_path("/a/%2e%2e/b"), Ok("/b".to_string()));
+        assert_eq!(normalise_path("/a/..%2fb"), Ok("/b".to_string()));
+    }
+
+    #[test]
+    fn rejects_climbing_above_the_root() {
+        for hostile in [
+            "/../x",
+            "/..",
+            "/a/../../x",
+            "/%2e%2e/x",
+            "/%2E%2e%2fetc/passwd",
+            "/..%2f",
+            "/..%2Fx",
+            "/a/%2e%2e%2f%2e%2e%2fx",
+        ] {
+            assert_eq!(
+                normalise_path(hostile),
+                Err(PathError::Forbidden),
+                "{}",
+                hostile
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_paths() {
+        for hostile in [
+            "x",
+            "",
+            "/a%00.html",
+            "/a%5c..%5cx",
+            "/a\\x",
+            "/%2",
+            "/a%",
+            "/%zz",
+            "/%ff",
+            "/%c3%28",
+        ] {
+            assert_eq!(
+                normalise_path(hostile),
+                Err(PathError::Malformed),
+                "{}",
+                hostile
+            );
+        }
+    }
+
+    #[test]
+    fn keeps_a_trailing_slash_for_trailing_dot_segments() {
+        assert_eq!(normalise_path("/a/"), Ok("/a/".to_string()));
+        assert_eq!(normalise_path("/a/."), Ok("/a/".to_string()));
+        assert_eq!(normalise_path("/a/b/.."), Ok("/a/".to_string()));
+        assert_eq!(normalise_path("/a/b/%2e%2e"), Ok("/a/".to_string()));
+        assert_eq!(normalise_path("/."), Ok("/".to_string()));
+        assert_eq!(normalise_path("/a/.."), Ok("/".to_string()));
+    }
+
+    #[test]
+    fn confines_symlinks_to_the_root() {
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::write(outside.path().join("secret"), "secret").unwrap();
+        let root_dir = tempfile::tempdir().unwrap();
+        let root = root_dir.path().canonicalize().unwrap();
+        std::fs::write(root.join("file"), "file").unwrap();
+        std::os::unix::fs::symlink(outside.path(), root.join("escape")).unwrap();
+        std::os::unix::fs::symlink(root.join("file"), root.join("inside")).unwrap();
+
+        assert_eq!(
+            confine_path(&root, "/escape/secret"),
+            Err(PathError::Forbidden)
+        );
+        assert_eq!(confine_path(&root, "/escape"), Err(PathError::Forbidden));
+        assert_eq!(confine_path(&root, "/inside"), Ok(root.join("file")));
+        assert_eq!(confine_path(&root, "/missing"), Ok(root.join("missing")));
+    }
+}
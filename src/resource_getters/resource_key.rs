@@ -0,0 +1,80 @@
+use hyper::header::HOST;
+use hyper::Request;
+
+use crate::resource_getters::path_sanitiser::{self, PathError};
+
+/// variant used for the unmodified representation of a resource
+const IDENTITY_VARIANT: &str = "identity";
+
+/// canonical identity of a requested resource, shared by the file resolver and the cache.
+/// Query strings and fragments are not part of the key, so cache-busting parameters
+/// resolve to the same file and the same cache entry.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ResourceKey {
+    // the serving site rather than the raw Host header, so arbitrary hosts can't flood the cache
+    host: String,
+    path: String,
+    variant: String,
+}
+
+impl ResourceKey {
+    /// builds the key from the request under the site's namespace, failing if the path is malformed or escapes the root
+    pub(crate) fn from_request<B>(req: &Request<B>, namespace: &str) -> Result<Self, PathError> {
+        Ok(Self {
+            host: namespace.to_string(),
+            path: path_sanitiser::normalise_path(req.uri().path())?,
+            variant: IDENTITY_VARIANT.to_string(),
+        })
+    }
+
+    /// the same resource under another representation, e.g. a directory listing format
+    pub(crate) fn with_variant(&self, variant: String) -> Self {
+        Self {
+            variant,
+            ..self.clone()
+        }
+    }
+
+    pub(crate) fn get_path(&self) -> &str {
+        &self.path
+    }
+
+    pub(crate) fn get_variant(&self) -> &str {
+        &self.variant
+    }
+}
+
+/// lowercased host without port or trailing dot, taken from the request target or Host header.
+/// Hosts containing anything other than hostname or ip literal characters become empty.
+pub(crate) fn normalise_host<B>(req: &Request<B>) -> String {
+    let raw_host = match req.uri().host() {
+        Some(host) => host,
+        None => match req.headers().get(HOST).and_then(|host| host.to_str().ok()) {
+            Some(host) => strip_port(host),
+            None => return String::new(),
+        },
+    };
+
+    let host = raw_host.trim_end_matches('.').to_ascii_lowercase();
+    let is_valid = host.chars().all(|character| {
+        character.is_ascii_alphanumeric() || matches!(character, '-' | '.' | '[' | ']' | ':')
+    });
+
+    if is_valid {
+        host
+    } else {
+        String::new()
+    }
+}
+
+/// removes the port from a Host header value, keeping ipv6 literals intact
+fn strip_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        match host.find(']') {
+            Some(end) => &host[..=end],
+            None => host,
+        }
+    } else {
+        host.split(':').next().unwrap_or(host)
+    }
+}
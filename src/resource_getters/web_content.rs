@@ -1,16 +1,33 @@
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::SystemTime;
 
+use futures_util::{future, stream, StreamExt};
 use hyper::body::Bytes;
 use hyper::Request;
 
-use crate::cache::Cache;
+use crate::cache::{Cache, CacheStatus};
+use crate::config::{CompressionConfig, ResourceConfig, Site};
 use crate::method_handlers::handler_utils;
+use crate::method_handlers::handler_utils::response_body::{self, ChunkStream, ResponseBody};
+use crate::resource_getters::content_encoding::{self, Encoding};
 use crate::resource_getters::dir_accessor;
+use crate::resource_getters::dir_listing::{self, ListingOptions};
+use crate::resource_getters::mime_types;
+use crate::resource_getters::path_sanitiser::{self, PathError};
+use crate::resource_getters::resource_key::ResourceKey;
+use crate::sendfile::SendfileQueue;
+
+/// the file a request resolves to, and the offered encodings it has a precompressed sibling for
+struct Precompressed {
+    resource_key: ResourceKey,
+    path: PathBuf,
+    siblings: Vec<(Encoding, PathBuf)>,
+}
 
 enum WebContentState {
     Content {
-        data: Bytes,
+        body: ResourceBody,
         content_type: String,
         last_modified: SystemTime,
         etag: String,
@@ -18,73 +35,255 @@ enum WebContentState {
     NotFound {
         data: Bytes,
     },
+    Rejected {
+        reason: PathError,
+    },
+    Redirect {
+        location: String,
+    },
+}
+
+/// body of a resource, held in memory or streamed from disk when it is large
+#[derive(Debug, Clone)]
+pub enum ResourceBody {
+    InMemory(Bytes),
+    File { path: PathBuf, length: u64 },
 }
 
 pub struct WebContent {
     state: WebContentState,
+    // request headers the representation was chosen by, sent back in Vary
+    vary: Vec<&'static str>,
+    content_encoding: Option<Encoding>,
+    // key and file of in-memory content, used to cache its compressed variants
+    cache_origin: Option<(ResourceKey, PathBuf)>,
+    cache_status: Option<CacheStatus>,
+}
+
+impl ResourceBody {
+    pub(crate) fn length(&self) -> u64 {
+        match self {
+            ResourceBody::InMemory(data) => data.len() as u64,
+            ResourceBody::File { length, .. } => *length,
+        }
+    }
+
+    /// the whole body, files being sent with sendfile when the connection has a queue
+    pub(crate) fn to_response_body(&self, sendfile: Option<&SendfileQueue>) -> ResponseBody {
+        match self {
+            ResourceBody::InMemory(data) => response_body::full(data.clone()),
+            ResourceBody::File { path, length } => {
+                response_body::from_chunks(file_chunks(path.clone(), 0, *length, sendfile))
+            }
+        }
+    }
+
+    /// bytes start to end inclusive, files are seeked to the start rather than read from the beginning
+    pub(crate) fn range_chunks(
+        &self,
+        start: u64,
+        end: u64,
+        sendfile: Option<&SendfileQueue>,
+    ) -> ChunkStream {
+        match self {
+            ResourceBody::InMemory(data) => {
+                // ranges are checked against the length before slicing
+                let slice = data.slice(start as usize..=end as usize);
+                stream::once(future::ok(slice)).boxed()
+            }
+            ResourceBody::File { path, .. } => {
+                file_chunks(path.clone(), start, end - start + 1, sendfile)
+            }
+        }
+    }
+}
+
+/// chunks of a file read from disk, or placeholders the connection sends the file in place of
+fn file_chunks(
+    path: PathBuf,
+    offset: u64,
+    length: u64,
+    sendfile: Option<&SendfileQueue>,
+) -> ChunkStream {
+    match sendfile {
+        Some(queue) => queue.file_chunks(path, offset, length),
+        None => response_body::file_chunks(path, offset, length),
+    }
 }
 
 impl WebContent {
     fn new_content(
-        data: Bytes,
+        body: ResourceBody,
         content_type: String,
         last_modified: SystemTime,
         etag: String,
     ) -> Self {
         Self {
             state: WebContentState::Content {
-                data,
+                body,
                 content_type,
                 last_modified,
                 etag,
             },
+            vary: Vec::new(),
+            content_encoding: None,
+            cache_origin: None,
+            cache_status: None,
         }
     }
 
     fn new_not_found(data: Bytes) -> Self {
         Self {
             state: WebContentState::NotFound { data },
+            vary: Vec::new(),
+            content_encoding: None,
+            cache_origin: None,
+            cache_status: None,
+        }
+    }
+
+    fn new_redirect(location: String) -> Self {
+        Self {
+            state: WebContentState::Redirect { location },
+            vary: Vec::new(),
+            content_encoding: None,
+            cache_origin: None,
+            cache_status: None,
+        }
+    }
+
+    fn new_rejected(reason: PathError) -> Self {
+        Self {
+            state: WebContentState::Rejected { reason },
+            vary: Vec::new(),
+            content_encoding: None,
+            cache_origin: None,
+            cache_status: None,
         }
     }
 
-    pub(crate) fn get_data(&self) -> &Bytes {
+    pub(crate) fn get_body(&self) -> Option<&ResourceBody> {
         match &self.state {
-            WebContentState::Content { data, .. } | WebContentState::NotFound { data } => data,
+            WebContentState::Content { body, .. } => Some(body),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn get_not_found_page(&self) -> Option<&Bytes> {
+        match &self.state {
+            WebContentState::NotFound { data } => Some(data),
+            _ => None,
         }
     }
 
     pub(crate) fn get_content_type(&self) -> Option<&String> {
         match &self.state {
             WebContentState::Content { content_type, .. } => Some(content_type),
-            WebContentState::NotFound { .. } => None,
+            _ => None,
         }
     }
 
     pub(crate) fn get_last_modified(&self) -> Option<&SystemTime> {
         match &self.state {
             WebContentState::Content { last_modified, .. } => Some(last_modified),
-            WebContentState::NotFound { .. } => None,
+            _ => None,
         }
     }
 
     pub(crate) fn get_etag(&self) -> Option<&String> {
         match &self.state {
             WebContentState::Content { etag, .. } => Some(etag),
-            WebContentState::NotFound { .. } => None,
+            _ => None,
         }
     }
 
     pub(crate) fn is_not_found(&self) -> bool {
         matches!(self.state, WebContentState::NotFound { .. })
     }
+
+    pub(crate) fn get_vary(&self) -> &[&'static str] {
+        &self.vary
+    }
+
+    pub(crate) fn get_cache_status(&self) -> Option<CacheStatus> {
+        self.cache_status
+    }
+
+    pub(crate) fn get_content_encoding(&self) -> Option<Encoding> {
+        self.content_encoding
+    }
+
+    pub(crate) fn get_redirect(&self) -> Option<&str> {
+        match &self.state {
+            WebContentState::Redirect { location } => Some(location),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn get_rejection(&self) -> Option<PathError> {
+        match &self.state {
+            WebContentState::Rejected { reason } => Some(*reason),
+            _ => None,
+        }
+    }
 }
 
+/// gets the content for a request, using a precompressed file or compressing it on the fly
+/// when the client accepts it and compression is worthwhile
 pub(crate) async fn get_web_content(
     req: &Request<hyper::body::Incoming>,
     cache: Arc<Cache>,
+    site: &Site<'_>,
+    compression_config: &CompressionConfig,
+) -> Option<WebContent> {
+    // looked up once, both to serve a sibling and to decide the Vary of the identity response
+    let precompressed = match site.resources.precompressed {
+        true => find_precompressed(req, site, compression_config).await,
+        false => None,
+    };
+    if let Some(precompressed) = &precompressed {
+        if let Some(web_content) = get_precompressed(
+            req,
+            Arc::clone(&cache),
+            site,
+            precompressed,
+            compression_config,
+        )
+        .await
+        {
+            return Some(web_content);
+        }
+    }
+
+    let mut web_content = get_identity_content(req, Arc::clone(&cache), site).await?;
+    if compression_config.enabled {
+        web_content = compress_content(req, cache, compression_config, web_content).await;
+    }
+
+    // a sibling the client didn't accept still makes the response vary
+    let has_sibling = precompressed.is_some_and(|precompressed| !precompressed.siblings.is_empty());
+    if has_sibling && !web_content.vary.contains(&"Accept-Encoding") {
+        web_content.vary.push("Accept-Encoding");
+    }
+    Some(web_content)
+}
+
+/// gets the uncompressed content from the cache or the filesystem
+async fn get_identity_content(
+    req: &Request<hyper::body::Incoming>,
+    cache: Arc<Cache>,
+    site: &Site<'_>,
 ) -> Option<WebContent> {
+    let resource_config = site.resources;
+
+    // Derive the canonical key, rejecting hostile paths before they reach the cache or filesystem
+    let resource_key = match ResourceKey::from_request(req, site.namespace) {
+        Ok(resource_key) => resource_key,
+        Err(reason) => return Some(WebContent::new_rejected(reason)),
+    };
+
     // Holds cache results
-    let cache_result = Cache::read_cache(Arc::clone(&cache), req.uri()).await;
+    let cache_result = Cache::read_cache(Arc::clone(&cache), &resource_key).await;
 
     // Variable indicating whether cache can be checked
     let can_check_cache = handler_utils::header_evals::can_check_cache(req.headers());
@@ -92,7 +291,7 @@ pub(crate) async fn get_web_content(
     // Variable holding the etag of the cache value (if found) to check for staleness
     let cache_etag = cache_result
         .clone()
-        .map(|(_, _, _, etag)| etag)
+        .map(|(_, _, _, etag, _)| etag)
         .unwrap_or("".to_string());
 
     // Content temporarily wrapped in an option
@@ -100,26 +299,61 @@ pub(crate) async fn get_web_content(
 
     // Check the cache for the requested resource
     if can_check_cache {
-        if let Some((data, content_type, last_modified, etag)) = cache_result {
-            wrapped_content = Some(WebContent::new_content(
-                data,
+        if let Some((data, content_type, last_modified, etag, path)) = cache_result {
+            let mut web_content = WebContent::new_content(
+                ResourceBody::InMemory(data),
                 content_type,
                 last_modified,
                 etag,
-            ));
+            );
+            web_content.cache_origin = Some((resource_key.clone(), path));
+            web_content.cache_status = Some(CacheStatus::Hit);
+            wrapped_content = Some(web_content);
         }
     }
 
     // If wasn't in cache or couldn't check cache, do a direct read
     if wrapped_content.is_none() {
-        match dir_accessor::retrieve_resource(req.uri()).await? {
-            (data, Some((content_type, last_modified))) => {
+        let mut path = match dir_accessor::resolve_path(&resource_key, resource_config) {
+            Ok(path) => path,
+            Err(reason) => return Some(WebContent::new_rejected(reason)),
+        };
+
+        // file the cache entry is invalidated by, for index files the directory, so a new
+        // or removed candidate index file changes which one is served
+        let cache_path = path.clone();
+
+        // directories serve their index file, or a listing when there is none and autoindex is on
+        if path.is_dir() {
+            // relative links in the page only resolve against the directory with a trailing slash
+            if !resource_key.get_path().ends_with('/') {
+                return Some(WebContent::new_redirect(directory_location(
+                    req,
+                    &resource_key,
+                )));
+            }
+
+            match dir_accessor::resolve_index_path(&resource_key, resource_config) {
+                Some(index_path) => path = index_path,
+                None if resource_config.autoindex.enabled => {
+                    return get_listing(req, cache, site, &resource_key, &path).await;
+                }
+                None => {
+                    let data = dir_accessor::retrieve_not_found_page(resource_config).await?;
+                    return Some(WebContent::new_not_found(data));
+                }
+            }
+        }
+
+        match dir_accessor::retrieve_resource(&path, resource_config).await? {
+            (ResourceBody::InMemory(data), Some((content_type, last_modified))) => {
                 let etag = Cache::generate_etag(&data);
                 // If wasn't in cache, or etags don't match
                 if cache_etag.is_empty() || cache_etag != etag {
                     Cache::write_cache(
                         Arc::clone(&cache),
-                        req.uri(),
+                        &resource_key,
+                        &cache_path,
                         &data,
                         &content_type,
                         &last_modified,
@@ -128,19 +362,325 @@ pub(crate) async fn get_web_content(
                     .await;
                 }
                 // Store read values in struct
-                wrapped_content = Some(WebContent::new_content(
-                    data,
+                let mut web_content = WebContent::new_content(
+                    ResourceBody::InMemory(data),
                     content_type,
                     last_modified,
                     etag,
-                ));
+                );
+                web_content.cache_origin = Some((resource_key.clone(), cache_path));
+                web_content.cache_status = Some(CacheStatus::Miss);
+                wrapped_content = Some(web_content);
             }
-            (data, None) => {
+            // large files bypass the cache and are streamed
+            (body @ ResourceBody::File { length, .. }, Some((content_type, last_modified))) => {
+                let etag = Cache::generate_file_etag(length, &last_modified);
+                let mut web_content =
+                    WebContent::new_content(body, content_type, last_modified, etag);
+                web_content.cache_status = Some(CacheStatus::Miss);
+                wrapped_content = Some(web_content);
+            }
+            (ResourceBody::InMemory(data), None) => {
                 // This represents a 404 page
                 wrapped_content = Some(WebContent::new_not_found(data));
             }
+            // the 404 page is always read into memory
+            (ResourceBody::File { .. }, None) => return None,
         }
     }
 
     Some(wrapped_content.unwrap())
 }
+
+/// gets a directory listing from the cache or renders it, each format and sort order being a separate cache variant
+async fn get_listing(
+    req: &Request<hyper::body::Incoming>,
+    cache: Arc<Cache>,
+    site: &Site<'_>,
+    resource_key: &ResourceKey,
+    dir: &Path,
+) -> Option<WebContent> {
+    let options = ListingOptions::from_request(req);
+    let listing_key = resource_key.with_variant(options.variant());
+
+    let cache_result = if handler_utils::header_evals::can_check_cache(req.headers()) {
+        Cache::read_cache(Arc::clone(&cache), &listing_key).await
+    } else {
+        None
+    };
+
+    let cache_status = match cache_result {
+        Some(_) => CacheStatus::Hit,
+        None => CacheStatus::Miss,
+    };
+    let (data, content_type, last_modified, etag) = match cache_result {
+        Some((data, content_type, last_modified, etag, _)) => {
+            (data, content_type, last_modified, etag)
+        }
+        None => {
+            let (data, content_type, last_modified) = dir_listing::render_listing(
+                dir,
+                resource_key.get_path(),
+                &options,
+                site.resources.autoindex.show_hidden,
+            )
+            .await?;
+            let etag = Cache::generate_etag(&data);
+            Cache::write_cache(
+                Arc::clone(&cache),
+                &listing_key,
+                dir,
+                &data,
+                &content_type,
+                &last_modified,
+                &etag,
+            )
+            .await;
+            (data, content_type, last_modified, etag)
+        }
+    };
+
+    let mut web_content = WebContent::new_content(
+        ResourceBody::InMemory(data),
+        content_type,
+        last_modified,
+        etag,
+    );
+    web_content.vary.push("Accept");
+    web_content.cache_origin = Some((listing_key, dir.to_path_buf()));
+    web_content.cache_status = Some(cache_status);
+    Some(web_content)
+}
+
+/// the directory's path with a trailing slash, keeping the query string
+fn directory_location(req: &Request<hyper::body::Incoming>, resource_key: &ResourceKey) -> String {
+    let mut location = format!("{}/", path_sanitiser::encode_path(resource_key.get_path()));
+    if let Some(query) = req.uri().query() {
+        location.push('?');
+        location.push_str(query);
+    }
+    location
+}
+
+/// serves the precompressed sibling for the encoding negotiated with the client, with the original's content type.
+/// None when there's no sibling for that encoding, leaving the request to the uncompressed path.
+async fn get_precompressed(
+    req: &Request<hyper::body::Incoming>,
+    cache: Arc<Cache>,
+    site: &Site<'_>,
+    precompressed: &Precompressed,
+    compression_config: &CompressionConfig,
+) -> Option<WebContent> {
+    let resource_config = site.resources;
+    let Precompressed {
+        resource_key,
+        path,
+        siblings,
+    } = precompressed;
+
+    let offered = offered_encodings(compression_config);
+    let encoding = content_encoding::negotiate(req.headers(), &offered)?;
+    let (_, sibling_path) = siblings
+        .iter()
+        .find(|(sibling_encoding, _)| *sibling_encoding == encoding)?;
+    let sibling_key = resource_key.with_variant(format!("precompressed+{}", encoding.token()));
+
+    let cache_result = if handler_utils::header_evals::can_check_cache(req.headers()) {
+        Cache::read_cache(Arc::clone(&cache), &sibling_key).await
+    } else {
+        None
+    };
+
+    let cache_status = match cache_result {
+        Some(_) => CacheStatus::Hit,
+        None => CacheStatus::Miss,
+    };
+    let mut web_content = match cache_result {
+        Some((data, content_type, last_modified, etag, _)) => WebContent::new_content(
+            ResourceBody::InMemory(data),
+            content_type,
+            last_modified,
+            etag,
+        ),
+        None => {
+            let (body, last_modified) =
+                match dir_accessor::retrieve_resource(sibling_path, resource_config).await? {
+                    (body, Some((_, last_modified))) => (body, last_modified),
+                    (_, None) => return None,
+                };
+            // typed from the original file, only its encoding differs
+            let content_type = mime_types::content_type(path, &[], resource_config);
+
+            let etag = match &body {
+                ResourceBody::InMemory(data) => {
+                    let etag = Cache::generate_etag(data);
+                    Cache::write_cache(
+                        Arc::clone(&cache),
+                        &sibling_key,
+                        sibling_path,
+                        data,
+                        &content_type,
+                        &last_modified,
+                        &etag,
+                    )
+                    .await;
+                    etag
+                }
+                // kept apart from the etag of an uncompressed file with the same size and mtime
+                ResourceBody::File { length, .. } => format!(
+                    "{}-{}",
+                    Cache::generate_file_etag(*length, &last_modified),
+                    encoding.token()
+                ),
+            };
+            WebContent::new_content(body, content_type, last_modified, etag)
+        }
+    };
+
+    web_content.vary.push("Accept-Encoding");
+    web_content.content_encoding = Some(encoding);
+    web_content.cache_status = Some(cache_status);
+    Some(web_content)
+}
+
+/// looks for precompressed siblings of the requested file on the blocking pool, since resolving the
+/// file and every candidate sibling touches the disk. None for redirects, listings and missing files
+async fn find_precompressed(
+    req: &Request<hyper::body::Incoming>,
+    site: &Site<'_>,
+    compression_config: &CompressionConfig,
+) -> Option<Precompressed> {
+    let resource_key = ResourceKey::from_request(req, site.namespace).ok()?;
+    let resource_config = site.resources.clone();
+    let offered = offered_encodings(compression_config);
+
+    tokio::task::spawn_blocking(move || {
+        let path = precompressed_source(&resource_key, &resource_config)?;
+        let siblings = offered
+            .into_iter()
+            .filter_map(|encoding| {
+                dir_accessor::resolve_precompressed_path(&path, encoding, &resource_config)
+                    .map(|sibling_path| (encoding, sibling_path))
+            })
+            .collect();
+        Some(Precompressed {
+            resource_key,
+            path,
+            siblings,
+        })
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+/// file a precompressed sibling would be found next to, None for redirects, listings and missing files
+fn precompressed_source(
+    resource_key: &ResourceKey,
+    resource_config: &ResourceConfig,
+) -> Option<PathBuf> {
+    let mut path = dir_accessor::resolve_path(resource_key, resource_config).ok()?;
+    if path.is_dir() {
+        // redirects and listings are left to the uncompressed path
+        if !resource_key.get_path().ends_with('/') {
+            return None;
+        }
+        path = dir_accessor::resolve_index_path(resource_key, resource_config)?;
+    }
+    path.is_file().then_some(path)
+}
+
+/// encodings the server is configured to send, in order of preference
+fn offered_encodings(compression_config: &CompressionConfig) -> Vec<Encoding> {
+    compression_config
+        .encodings
+        .iter()
+        .filter_map(|token| Encoding::from_token(token))
+        .collect()
+}
+
+/// swaps in-memory content for its compressed variant, compressing it only if the variant isn't cached.
+/// Tiny, incompressible and streamed content is left as it is.
+async fn compress_content(
+    req: &Request<hyper::body::Incoming>,
+    cache: Arc<Cache>,
+    compression_config: &CompressionConfig,
+    mut web_content: WebContent,
+) -> WebContent {
+    let (resource_key, path) = match &web_content.cache_origin {
+        Some(cache_origin) => cache_origin.clone(),
+        None => return web_content,
+    };
+    let (data, content_type, last_modified, etag) = match &web_content.state {
+        WebContentState::Content {
+            body: ResourceBody::InMemory(data),
+            content_type,
+            last_modified,
+            etag,
+        } => (
+            data.clone(),
+            content_type.clone(),
+            *last_modified,
+            etag.clone(),
+        ),
+        _ => return web_content,
+    };
+    if data.len() < compression_config.min_bytes || !mime_types::is_compressible(&content_type) {
+        return web_content;
+    }
+
+    // the response depends on Accept-Encoding whichever encoding ends up being used
+    web_content.vary.push("Accept-Encoding");
+
+    let offered = offered_encodings(compression_config);
+    let encoding = match content_encoding::negotiate(req.headers(), &offered) {
+        Some(encoding) => encoding,
+        None => return web_content,
+    };
+
+    // derived from the identity etag, so a cached variant of an older version is never served
+    let encoded_etag = format!("{}-{}", etag, encoding.token());
+    let encoded_key = resource_key.with_variant(format!(
+        "{}+{}",
+        resource_key.get_variant(),
+        encoding.token()
+    ));
+
+    let encoded_data = match Cache::read_cache(Arc::clone(&cache), &encoded_key).await {
+        Some((encoded_data, _, _, cached_etag, _)) if cached_etag == encoded_etag => encoded_data,
+        _ => {
+            let compress_data = data.clone();
+            let compressed =
+                tokio::task::spawn_blocking(move || encoding.compress(&compress_data)).await;
+            let encoded_data = match compressed {
+                Ok(Ok(encoded_data)) => encoded_data,
+                _ => return web_content,
+            };
+            // already compressed formats can grow, those are sent as they are
+            if encoded_data.len() >= data.len() {
+                return web_content;
+            }
+            web_content.cache_status = Some(CacheStatus::Miss);
+            Cache::write_cache(
+                Arc::clone(&cache),
+                &encoded_key,
+                &path,
+                &encoded_data,
+                &content_type,
+                &last_modified,
+                &encoded_etag,
+            )
+            .await;
+            encoded_data
+        }
+    };
+
+    web_content.state = WebContentState::Content {
+        body: ResourceBody::InMemory(encoded_data),
+        content_type,
+        last_modified,
+        etag: encoded_etag,
+    };
+    web_content.content_encoding = Some(encoding);
+    web_content
+}
@@ -0,0 +1,374 @@
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::io::{self, IoSlice};
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use futures_util::{stream, StreamExt, TryStreamExt};
+use hyper::body::Bytes;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::Response;
+use hyper_util::rt::TokioIo;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncWrite, Interest, ReadBuf};
+use tokio::net::TcpStream;
+use tracing::warn;
+
+use crate::method_handlers::handler_utils::response_body::{self, ChunkStream};
+
+/// size of the placeholder chunks standing in for file data
+const PLACEHOLDER_SIZE: usize = 64 * 1024;
+
+/// handed to hyper in place of file data. SendfileStream recognises these bytes by their
+/// address and has the kernel send the queued file instead, so file data never reaches user space
+static PLACEHOLDER: [u8; PLACEHOLDER_SIZE] = [0; PLACEHOLDER_SIZE];
+
+/// body length of the response served when probing hyper, a few placeholder chunks
+const PROBE_BYTES: u64 = 3 * PLACEHOLDER_SIZE as u64;
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// files waiting to be sent on a connection, in the order hyper writes their responses.
+/// Only handed to http/1.1 requests on plaintext connections, where body bytes go to the socket as they are.
+#[derive(Clone, Default)]
+pub(crate) struct SendfileQueue {
+    files: Arc<Mutex<VecDeque<QueuedFile>>>,
+}
+
+/// the part of a file still to be sent
+struct QueuedFile {
+    file: std::fs::File,
+    offset: u64,
+    remaining: u64,
+}
+
+/// plaintext tcp stream that sends queued files wherever hyper writes placeholder chunks
+pub(crate) struct SendfileStream {
+    stream: TcpStream,
+    queue: SendfileQueue,
+}
+
+/// in-memory connection that sends one request and records how the response body reaches it
+struct ProbeStream {
+    request: &'static [u8],
+    placeholder_bytes: u64,
+    copied_placeholder: bool,
+}
+
+/// true where the kernel can send files to sockets and hyper hands placeholder chunks to the
+/// connection as they are. If hyper ever copied body chunks into its own buffer, the zeros would be
+/// sent in place of the file, so sendfile is left off and files are streamed through user space.
+pub(crate) async fn available() -> bool {
+    if !cfg!(target_os = "linux") {
+        return false;
+    }
+    let intact = placeholders_reach_connection().await;
+    if !intact {
+        warn!("hyper does not write body chunks as they are, streamed files are sent without sendfile");
+    }
+    intact
+}
+
+/// serves a response of placeholder chunks over a probe connection, the same way site connections are served
+async fn placeholders_reach_connection() -> bool {
+    let mut probe = ProbeStream {
+        request: b"GET / HTTP/1.1\r\nHost: probe\r\nConnection: close\r\n\r\n",
+        placeholder_bytes: 0,
+        copied_placeholder: false,
+    };
+    let service = service_fn(|_req| async {
+        Ok::<_, Infallible>(Response::new(response_body::from_chunks(
+            placeholder_chunks(PROBE_BYTES),
+        )))
+    });
+    let connection = http1::Builder::new().serve_connection(TokioIo::new(&mut probe), service);
+
+    match tokio::time::timeout(PROBE_TIMEOUT, connection).await {
+        Ok(Ok(())) => probe.placeholder_bytes == PROBE_BYTES && !probe.copied_placeholder,
+        _ => false,
+    }
+}
+
+impl SendfileQueue {
+    /// stands in for length bytes of a file from offset, the file is only opened and queued once the body is polled
+    pub(crate) fn file_chunks(&self, path: PathBuf, offset: u64, length: u64) -> ChunkStream {
+        let queue = self.clone();
+        stream::once(async move {
+            // nothing is written for an empty body, so nothing is queued
+            if length > 0 {
+                let file = File::open(&path).await?.into_std().await;
+                queue.files.lock().unwrap().push_back(QueuedFile {
+                    file,
+                    offset,
+                    remaining: length,
+                });
+            }
+            Ok::<_, io::Error>(placeholder_chunks(length))
+        })
+        .try_flatten()
+        .boxed()
+    }
+}
+
+impl SendfileStream {
+    pub(crate) fn new(stream: TcpStream, queue: SendfileQueue) -> Self {
+        Self { stream, queue }
+    }
+
+    /// sends up to length bytes of the file at the front of the queue
+    fn poll_send_file(&self, cx: &mut Context<'_>, length: usize) -> Poll<io::Result<usize>> {
+        let mut files = self.queue.files.lock().unwrap();
+        let queued = match files.front_mut() {
+            Some(queued) => queued,
+            None => {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "placeholder written with no file queued",
+                )))
+            }
+        };
+        // placeholders of the next response may follow in the same write
+        let length = length.min(usize::try_from(queued.remaining).unwrap_or(usize::MAX));
+
+        let sent = loop {
+            ready!(self.stream.poll_write_ready(cx))?;
+            match self.stream.try_io(Interest::WRITABLE, || {
+                send_file(self.stream.as_raw_fd(), queued, length)
+            }) {
+                Ok(sent) => break sent,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+        };
+
+        queued.offset += sent as u64;
+        queued.remaining -= sent as u64;
+        if queued.remaining == 0 {
+            files.pop_front();
+        }
+        Poll::Ready(Ok(sent))
+    }
+}
+
+impl AsyncRead for SendfileStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for SendfileStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.poll_write_vectored(cx, &[IoSlice::new(buf)])
+    }
+
+    // writes the leading run of ordinary buffers, or sends the file for a leading run of placeholders
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let bufs = match bufs.iter().position(|buf| !buf.is_empty()) {
+            Some(first) => &bufs[first..],
+            None => return Poll::Ready(Ok(0)),
+        };
+
+        if is_placeholder(&bufs[0]) {
+            let length = bufs
+                .iter()
+                .take_while(|buf| is_placeholder(buf))
+                .map(|buf| buf.len())
+                .sum();
+            self.poll_send_file(cx, length)
+        } else {
+            let end = bufs
+                .iter()
+                .position(|buf| is_placeholder(buf))
+                .unwrap_or(bufs.len());
+            Pin::new(&mut self.stream).poll_write_vectored(cx, &bufs[..end])
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        // hyper only queues body chunks as separate buffers, rather than copying them, for vectored io
+        true
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+impl AsyncRead for ProbeStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        // the request asks for the connection to close, so nothing is read after it
+        if self.request.is_empty() {
+            return Poll::Pending;
+        }
+        let length = self.request.len().min(buf.remaining());
+        buf.put_slice(&self.request[..length]);
+        self.request = &self.request[length..];
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for ProbeStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.poll_write_vectored(cx, &[IoSlice::new(buf)])
+    }
+
+    // response heads never contain NUL, so any zero outside the placeholder is a copied placeholder
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        for buf in bufs {
+            if is_placeholder(buf) {
+                self.placeholder_bytes += buf.len() as u64;
+            } else if buf.contains(&0) {
+                self.copied_placeholder = true;
+            }
+        }
+        Poll::Ready(Ok(bufs.iter().map(|buf| buf.len()).sum()))
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// placeholder chunks adding up to length bytes
+fn placeholder_chunks(length: u64) -> ChunkStream {
+    stream::iter((0..length).step_by(PLACEHOLDER_SIZE).map(move |start| {
+        let chunk_length = (length - start).min(PLACEHOLDER_SIZE as u64) as usize;
+        Ok(Bytes::from_static(&PLACEHOLDER[..chunk_length]))
+    }))
+    .boxed()
+}
+
+/// true if the buffer lies within the placeholder
+fn is_placeholder(buf: &[u8]) -> bool {
+    !buf.is_empty() && PLACEHOLDER.as_ptr_range().contains(&buf.as_ptr())
+}
+
+/// sends up to length bytes of the file from its offset, without moving the file's own position.
+/// splice would need a pipe between the file and the socket, two calls per chunk for the same
+/// zero-copy transfer that sendfile already makes (it is built on splice in the kernel).
+#[cfg(target_os = "linux")]
+fn send_file(socket: RawFd, queued: &QueuedFile, length: usize) -> io::Result<usize> {
+    let mut offset = libc::off_t::try_from(queued.offset)
+        .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    // SAFETY: both descriptors are open for the duration of the call and offset is a valid off_t
+    let sent = unsafe { libc::sendfile(socket, queued.file.as_raw_fd(), &mut offset, length) };
+    match sent {
+        // the file was truncated after its length was taken
+        0 => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+        sent if sent < 0 => Err(io::Error::last_os_error()),
+        sent => Ok(sent as usize),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_file(_socket: RawFd, _queued: &QueuedFile, _length: usize) -> io::Result<usize> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn hyper_writes_placeholders_untouched() {
+        assert!(placeholders_reach_connection().await);
+    }
+
+    /// a connected pair of sockets, the first wrapped to send files from the queue
+    async fn socket_pair(queue: &SendfileQueue) -> (SendfileStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (SendfileStream::new(server, queue.clone()), client)
+    }
+
+    #[tokio::test]
+    async fn sends_the_file_in_place_of_placeholders() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        let content: Vec<u8> = (0..200_000).map(|index| (index % 255 + 1) as u8).collect();
+        file.write_all(&content).unwrap();
+
+        let queue = SendfileQueue::default();
+        let (mut server, mut client) = socket_pair(&queue).await;
+        let chunks: Vec<Bytes> = queue
+            .file_chunks(file.path().to_path_buf(), 1000, 150_000)
+            .try_collect()
+            .await
+            .unwrap();
+
+        let mut bufs = vec![IoSlice::new(b"head ")];
+        bufs.extend(chunks.iter().map(|chunk| IoSlice::new(chunk)));
+        bufs.push(IoSlice::new(b" tail"));
+        let mut remaining: &mut [IoSlice<'_>] = &mut bufs;
+        while !remaining.is_empty() {
+            let written =
+                std::future::poll_fn(|cx| Pin::new(&mut server).poll_write_vectored(cx, remaining))
+                    .await
+                    .unwrap();
+            IoSlice::advance_slices(&mut remaining, written);
+        }
+        server.shutdown().await.unwrap();
+
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(&received[..5], b"head ");
+        assert_eq!(&received[5..150_005], &content[1000..151_000]);
+        assert_eq!(&received[150_005..], b" tail");
+        assert!(!received.contains(&0));
+    }
+
+    #[tokio::test]
+    async fn refuses_placeholders_without_a_queued_file() {
+        let queue = SendfileQueue::default();
+        let (mut server, _client) = socket_pair(&queue).await;
+        let chunk = Bytes::from_static(&PLACEHOLDER[..10]);
+        assert!(server.write_all(&chunk).await.is_err());
+    }
+}
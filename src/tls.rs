@@ -0,0 +1,408 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use notify::{Config as NotifyConfig, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+use tracing::{info, warn};
+
+use crate::config::{CertificateConfig, TlsConfig};
+
+/// time to wait for further file events before reloading, so a cert and key written together load once
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// error produced while loading certificates or building the tls config
+#[derive(Debug)]
+pub enum TlsError {
+    Read(PathBuf, std::io::Error),
+    NoCertificates(PathBuf),
+    NoPrivateKey(PathBuf),
+    Rustls(PathBuf, tokio_rustls::rustls::Error),
+    Config(tokio_rustls::rustls::Error),
+    Watch(notify::Error),
+}
+
+/// certificates loaded from the config, indexed by the hostnames they serve
+#[derive(Debug)]
+struct CertificateStore {
+    exact: HashMap<String, Arc<CertifiedKey>>,
+    // "*.example.com" is stored as "example.com"
+    wildcard: HashMap<String, Arc<CertifiedKey>>,
+    default: Arc<CertifiedKey>,
+}
+
+/// picks a certificate for each handshake from the SNI name, falling back to the first configured certificate
+#[derive(Debug)]
+pub struct CertificateResolver {
+    store: RwLock<Arc<CertificateStore>>,
+}
+
+/// builds the acceptor for the tls listeners, advertising h2 over ALPN only when http/2 is enabled
+pub(crate) fn build_acceptor(
+    tls_config: &TlsConfig,
+    http2_enabled: bool,
+) -> Result<(TlsAcceptor, Arc<CertificateResolver>), TlsError> {
+    let resolver = Arc::new(CertificateResolver {
+        store: RwLock::new(Arc::new(CertificateStore::load(&tls_config.certificates)?)),
+    });
+
+    let mut server_config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(TlsError::Config)?
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::clone(&resolver) as Arc<dyn ResolvesServerCert>);
+
+    if http2_enabled {
+        server_config.alpn_protocols.push(b"h2".to_vec());
+    }
+    server_config.alpn_protocols.push(b"http/1.1".to_vec());
+
+    Ok((TlsAcceptor::from(Arc::new(server_config)), resolver))
+}
+
+/// reloads every certificate when one of the configured cert or key files changes.
+/// A failed reload is reported and the previous certificates stay in use.
+pub(crate) fn spawn_certificate_watcher(
+    resolver: Arc<CertificateResolver>,
+    tls_config: &TlsConfig,
+) -> Result<(), TlsError> {
+    let certificates = tls_config.certificates.clone();
+    let watched_files: Vec<PathBuf> = certificates
+        .iter()
+        .flat_map(|certificate| [certificate.cert.clone(), certificate.key.clone()])
+        .collect();
+
+    // watch the parent directories, editors and cert tools usually replace files rather than write them
+    let (event_sender, mut event_receiver) = mpsc::unbounded_channel();
+    let mut watcher = RecommendedWatcher::new(
+        move |event| {
+            let _ = event_sender.send(event);
+        },
+        NotifyConfig::default(),
+    )
+    .map_err(TlsError::Watch)?;
+
+    // config validation made the paths absolute with canonical parent directories
+    for file in &watched_files {
+        if let Some(directory) = file.parent() {
+            watcher
+                .watch(directory, RecursiveMode::NonRecursive)
+                .map_err(TlsError::Watch)?;
+        }
+    }
+
+    tokio::task::spawn(async move {
+        // moved in so the watch lives as long as the task
+        let _watcher = watcher;
+
+        while let Some(event) = event_receiver.recv().await {
+            let is_relevant = match event {
+                Ok(event) => event.paths.iter().any(|path| watched_files.contains(path)),
+                Err(_) => false,
+            };
+            if !is_relevant {
+                continue;
+            }
+
+            // let the rest of the burst arrive before reading the files
+            tokio::time::sleep(RELOAD_DEBOUNCE).await;
+            while event_receiver.try_recv().is_ok() {}
+
+            match CertificateStore::load(&certificates) {
+                Ok(store) => {
+                    *resolver.store.write().unwrap() = Arc::new(store);
+                    info!("Reloaded TLS certificates");
+                }
+                Err(err) => warn!(%err, "Keeping previous TLS certificates"),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+impl CertificateStore {
+    /// reads every configured certificate, the first one becomes the default
+    fn load(certificates: &[CertificateConfig]) -> Result<Self, TlsError> {
+        let mut exact = HashMap::new();
+        let mut wildcard = HashMap::new();
+        let mut default = None;
+
+        for certificate in certificates {
+            let certified_key = Arc::new(load_certified_key(certificate)?);
+
+            for hostname in &certificate.hostnames {
+                let hostname = hostname.trim_end_matches('.').to_ascii_lowercase();
+                match hostname.strip_prefix("*.") {
+                    Some(parent) => wildcard.insert(parent.to_string(), Arc::clone(&certified_key)),
+                    None => exact.insert(hostname, Arc::clone(&certified_key)),
+                };
+            }
+            default.get_or_insert(certified_key);
+        }
+
+        Ok(Self {
+            exact,
+            wildcard,
+            // config validation guarantees at least one certificate
+            default: default.expect("no tls certificates configured"),
+        })
+    }
+
+    /// certificate for an SNI name, a wildcard matches exactly one extra label
+    fn lookup(&self, server_name: Option<&str>) -> Arc<CertifiedKey> {
+        let server_name = match server_name {
+            Some(server_name) => server_name.trim_end_matches('.').to_ascii_lowercase(),
+            None => return Arc::clone(&self.default),
+        };
+
+        if let Some(certified_key) = self.exact.get(&server_name) {
+            return Arc::clone(certified_key);
+        }
+
+        let wildcard_match = server_name
+            .split_once('.')
+            .and_then(|(_, parent)| self.wildcard.get(parent));
+        Arc::clone(wildcard_match.unwrap_or(&self.default))
+    }
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let store = Arc::clone(&self.store.read().unwrap());
+        Some(store.lookup(client_hello.server_name()))
+    }
+}
+
+/// reads a PEM certificate chain and private key
+fn load_certified_key(certificate: &CertificateConfig) -> Result<CertifiedKey, TlsError> {
+    let cert_file = File::open(&certificate.cert)
+        .map_err(|err| TlsError::Read(certificate.cert.clone(), err))?;
+    let cert_chain = rustls_pemfile::certs(&mut BufReader::new(cert_file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| TlsError::Read(certificate.cert.clone(), err))?;
+    if cert_chain.is_empty() {
+        return Err(TlsError::NoCertificates(certificate.cert.clone()));
+    }
+
+    let key_file =
+        File::open(&certificate.key).map_err(|err| TlsError::Read(certificate.key.clone(), err))?;
+    let private_key = rustls_pemfile::private_key(&mut BufReader::new(key_file))
+        .map_err(|err| TlsError::Read(certificate.key.clone(), err))?
+        .ok_or_else(|| TlsError::NoPrivateKey(certificate.key.clone()))?;
+
+    let signing_key = ring::sign::any_supported_type(&private_key)
+        .map_err(|err| TlsError::Rustls(certificate.key.clone(), err))?;
+
+    let certified_key = CertifiedKey::new(cert_chain, signing_key);
+    certified_key
+        .keys_match()
+        .map_err(|err| TlsError::Rustls(certificate.key.clone(), err))?;
+    Ok(certified_key)
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsError::Read(path, err) => write!(f, "could not read {}: {}", path.display(), err),
+            TlsError::NoCertificates(path) => {
+                write!(f, "no certificates found in {}", path.display())
+            }
+            TlsError::NoPrivateKey(path) => write!(f, "no private key found in {}", path.display()),
+            TlsError::Rustls(path, err) => write!(f, "{}: {}", path.display(), err),
+            TlsError::Config(err) => write!(f, "invalid tls settings: {}", err),
+            TlsError::Watch(err) => write!(f, "could not watch certificate files: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+    use tokio_rustls::TlsConnector;
+
+    use super::*;
+
+    /// a self-signed certificate for the hostnames, written to dir as name.pem and name.key
+    fn write_certificate(dir: &Path, name: &str, hostnames: &[&str]) -> CertificateConfig {
+        let subject_alt_names: Vec<String> =
+            hostnames.iter().map(|name| name.to_string()).collect();
+        let certified_key = rcgen::generate_simple_self_signed(subject_alt_names).unwrap();
+
+        let cert = dir.join(format!("{}.pem", name));
+        let key = dir.join(format!("{}.key", name));
+        std::fs::write(&cert, certified_key.cert.pem()).unwrap();
+        std::fs::write(&key, certified_key.key_pair.serialize_pem()).unwrap();
+        CertificateConfig {
+            hostnames: hostnames.iter().map(|name| name.to_string()).collect(),
+            cert,
+            key,
+        }
+    }
+
+    fn tls_config(certificates: Vec<CertificateConfig>) -> TlsConfig {
+        TlsConfig {
+            listen: Vec::new(),
+            certificates,
+            reload: false,
+        }
+    }
+
+    fn der(certificate: &CertificateConfig) -> CertificateDer<'static> {
+        let cert_file = File::open(&certificate.cert).unwrap();
+        rustls_pemfile::certs(&mut BufReader::new(cert_file))
+            .next()
+            .unwrap()
+            .unwrap()
+    }
+
+    /// accepts one handshake and returns the certificate and protocol a client trusting every
+    /// given certificate was offered for the server name
+    async fn handshake(
+        acceptor: TlsAcceptor,
+        trusted: &[&CertificateConfig],
+        server_name: &str,
+        alpn_protocols: &[&[u8]],
+    ) -> (CertificateDer<'static>, Option<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let _ = acceptor.accept(stream).await;
+        });
+
+        let mut roots = RootCertStore::empty();
+        for certificate in trusted {
+            roots.add(der(certificate)).unwrap();
+        }
+        let mut client_config =
+            ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+        client_config.alpn_protocols = alpn_protocols
+            .iter()
+            .map(|protocol| protocol.to_vec())
+            .collect();
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let tls_stream = TlsConnector::from(Arc::new(client_config))
+            .connect(
+                ServerName::try_from(server_name.to_string()).unwrap(),
+                stream,
+            )
+            .await
+            .unwrap();
+        let (_, connection) = tls_stream.get_ref();
+        (
+            connection.peer_certificates().unwrap()[0].clone(),
+            connection.alpn_protocol().map(|protocol| protocol.to_vec()),
+        )
+    }
+
+    #[tokio::test]
+    async fn selects_certificates_by_sni() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = write_certificate(dir.path(), "first", &["first.test"]);
+        let wildcard = write_certificate(dir.path(), "wildcard", &["*.second.test"]);
+        let (acceptor, _) =
+            build_acceptor(&tls_config(vec![first.clone(), wildcard.clone()]), true).unwrap();
+        let trusted = [&first, &wildcard];
+
+        let (certificate, _) = handshake(acceptor.clone(), &trusted, "first.test", &[]).await;
+        assert_eq!(certificate, der(&first));
+
+        let (certificate, _) = handshake(acceptor, &trusted, "www.second.test", &[]).await;
+        assert_eq!(certificate, der(&wildcard));
+    }
+
+    #[test]
+    fn falls_back_to_the_first_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = write_certificate(dir.path(), "first", &["first.test"]);
+        let wildcard = write_certificate(dir.path(), "wildcard", &["*.second.test"]);
+        let store = CertificateStore::load(&[first.clone(), wildcard.clone()]).unwrap();
+        let cert_of = |server_name| store.lookup(server_name).cert[0].clone();
+
+        assert_eq!(cert_of(Some("WWW.Second.Test.")), der(&wildcard));
+        // a wildcard covers exactly one label
+        assert_eq!(cert_of(Some("a.www.second.test")), der(&first));
+        assert_eq!(cert_of(Some("second.test")), der(&first));
+        assert_eq!(cert_of(Some("unknown.test")), der(&first));
+        assert_eq!(cert_of(None), der(&first));
+    }
+
+    #[tokio::test]
+    async fn negotiates_h2_only_when_enabled() {
+        let dir = tempfile::tempdir().unwrap();
+        let certificate = write_certificate(dir.path(), "site", &["site.test"]);
+        let offered: &[&[u8]] = &[b"h2", b"http/1.1"];
+
+        let (acceptor, _) = build_acceptor(&tls_config(vec![certificate.clone()]), true).unwrap();
+        let (_, protocol) = handshake(acceptor, &[&certificate], "site.test", offered).await;
+        assert_eq!(protocol.as_deref(), Some(&b"h2"[..]));
+
+        let (acceptor, _) = build_acceptor(&tls_config(vec![certificate.clone()]), false).unwrap();
+        let (_, protocol) = handshake(acceptor, &[&certificate], "site.test", offered).await;
+        assert_eq!(protocol.as_deref(), Some(&b"http/1.1"[..]));
+    }
+
+    #[tokio::test]
+    async fn reloads_replaced_certificates() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir_path = dir.path().canonicalize().unwrap();
+        let original = write_certificate(&dir_path, "site", &["site.test"]);
+        let original_der = der(&original);
+        let tls_config = tls_config(vec![original.clone()]);
+        let (_, resolver) = build_acceptor(&tls_config, true).unwrap();
+        spawn_certificate_watcher(Arc::clone(&resolver), &tls_config).unwrap();
+
+        // written under the same paths, as a renewal would
+        let renewed = write_certificate(&dir_path, "site", &["site.test"]);
+        let renewed_der = der(&renewed);
+        assert_ne!(original_der, renewed_der);
+
+        let served = || {
+            let store = Arc::clone(&resolver.store.read().unwrap());
+            store.lookup(Some("site.test")).cert[0].clone()
+        };
+        for _ in 0..50 {
+            if served() == renewed_der {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("certificate was not reloaded");
+    }
+
+    #[test]
+    fn rejects_a_key_that_does_not_match() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = write_certificate(dir.path(), "first", &["first.test"]);
+        let second = write_certificate(dir.path(), "second", &["second.test"]);
+        let mismatched = CertificateConfig {
+            key: second.key,
+            ..first
+        };
+        assert!(matches!(
+            CertificateStore::load(&[mismatched]),
+            Err(TlsError::Rustls(..))
+        ));
+    }
+}